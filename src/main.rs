@@ -1,31 +1,836 @@
+use clap::{Args, CommandFactory, Parser, Subcommand, ValueEnum};
 use hyprland::{
-    data::{Clients, CursorPosition, Monitors},
+    data::{Client, Clients, CursorPosition, Monitor, Monitors, Version, Workspace},
     dispatch::*,
     event_listener::EventListener,
-    keyword::Keyword,
+    keyword::{Keyword, OptionValue},
     shared::*,
 };
+use indexmap::IndexMap;
 use regex::Regex;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::{
-    collections::HashMap,
-    env, fs,
-    process::{Command, Stdio},
-    sync::Arc,
+    cell::RefCell,
+    collections::{BTreeMap, HashMap, HashSet},
+    env,
+    fmt::Write as _,
+    fs,
+    os::{
+        linux::net::SocketAddrExt,
+        unix::{
+            ffi::OsStrExt,
+            net::{SocketAddr, UnixDatagram},
+            process::CommandExt,
+        },
+    },
+    path::{Path, PathBuf},
+    pin::Pin,
+    process::{Child, Command, Stdio},
+    rc::Rc,
+    time::{Duration, Instant},
 };
-use zbus::{Connection, proxy};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, UnixListener, UnixStream},
+    signal::unix::{SignalKind, signal},
+    task::{JoinSet, LocalSet},
+};
+use zbus::{Connection, names::BusName, proxy};
 
 const PADDING: i64 = 20;
+const NO_PLASMOIDS: &str = "no plasmoids configured; add entries to ~/.config/hypr/plasmoids.json";
+
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if env::var_os("HYPR_PLASMOID_DEBUG").is_some() {
+            eprintln!($($arg)*);
+        }
+    };
+}
 
-#[derive(Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone)]
 struct Plasmoid {
-    title: String,
+    // One title or a list of alternatives, e.g. for localized window titles.
+    // Titles are regexes, so a literal `$` or `^` needs escaping.
+    #[serde(deserialize_with = "one_or_many")]
+    title: Vec<String>,
+    // Empty for entries with a `command`.
+    #[serde(default)]
     plasmoid: String,
+    // Launches this instead of plasmawindowed, for windowed apps that aren't
+    // Plasma applets. Such windows are never reused through SNI.
+    #[serde(default)]
+    command: Option<String>,
+    // Only windows of this class match, on top of the title.
+    #[serde(default)]
+    class: Option<String>,
+    #[serde(default)]
     width: u32,
+    #[serde(default)]
     height: u32,
+    #[serde(default)]
+    title_prefix: bool,
+    #[serde(default)]
+    position_command: Option<String>,
+    #[serde(default)]
+    bind: Option<String>,
+    #[serde(default = "default_true")]
+    exclusive: bool,
+    #[serde(default)]
+    anchor: Option<Anchor>,
+    #[serde(default)]
+    margin: Margin,
+    #[serde(default = "default_true")]
+    focus_on_show: bool,
+    // Non-focusable plasmoids, like overlays, never take focus and are
+    // dismissed when another window opens or gains focus instead.
+    #[serde(default = "default_true")]
+    focusable: bool,
+    #[serde(default)]
+    size: SizeMode,
+    #[serde(default)]
+    on_show: Option<String>,
+    #[serde(default)]
+    on_hide: Option<String>,
+    // Hyprland dispatches like "togglespecialworkspace scratch", run before
+    // the on_show/on_hide commands.
+    #[serde(default)]
+    on_show_dispatch: Vec<String>,
+    #[serde(default)]
+    on_hide_dispatch: Vec<String>,
+    #[serde(default)]
+    ready_title: Option<String>,
+    #[serde(default)]
+    ready_delay_ms: u64,
+    #[serde(default)]
+    scrim: bool,
+    #[serde(default)]
+    cooldown_ms: u64,
+    #[serde(default)]
+    close_action: CloseAction,
+    // Title of the settings dialog opened by `config`, "<title> Settings" if
+    // unset.
+    #[serde(default)]
+    config_title: Option<String>,
+    // Moves an already open plasmoid to the cursor when it's shown again.
+    #[serde(default)]
+    reposition_on_reshow: bool,
+    // Moves an open plasmoid over to the focused monitor when it's shown
+    // from another one, instead of just refocusing it.
+    #[serde(default)]
+    follow_to_cursor_monitor: bool,
+    // Never auto-hidden on focus loss; only explicit commands close it.
+    #[serde(default)]
+    sticky: bool,
+    // Auto-hiding leaves the plasmoid alone for this long after it's shown,
+    // so focus slipping away right after opening doesn't close it again.
+    #[serde(default)]
+    min_visible_ms: u64,
+    #[serde(default = "default_true")]
+    detach: bool,
+    // Always opens on this workspace; workspace changes don't hide it.
+    #[serde(default)]
+    workspace: Option<String>,
+    // Follow the plasmoid to its workspace instead of staying put.
+    #[serde(default)]
+    switch_to_workspace: bool,
+    // Hides the plasmoid once it's gone this long without getting focus back
+    // or the cursor passing over it. Only timed by the daemon.
+    #[serde(default)]
+    idle_hide_ms: u64,
+    // Shows an instance on every monitor at once, placed by the anchor (the
+    // center without one) on each.
+    #[serde(default)]
+    mirror: bool,
+    // Keeps the window entirely on the monitor. Without it, margins or the
+    // cursor can leave part of it past an edge, e.g. for a peeking drawer.
+    #[serde(default = "default_true")]
+    clamp: bool,
+    // Where `anchor: "focused-window"` puts the plasmoid: which side of the
+    // window, and how far from it.
+    #[serde(default)]
+    window_side: Side,
+    #[serde(default = "default_window_gap")]
+    window_gap: i64,
+    // Multiplies width and height (or the remembered size) by the scale of
+    // the monitor the plasmoid opens on, for sizes picked on a 1x monitor.
+    #[serde(default)]
+    scale_with_monitor: bool,
+    // "special" keeps the window on its own special workspace and toggles
+    // that instead of floating and moving it; placement options don't apply.
+    #[serde(default)]
+    mode: Mode,
+    // Window rule props like "opacity 0.9" or "pin", set on the plasmoid's
+    // rule after the ones hypr-plasmoid sets itself.
+    #[serde(default)]
+    extra_rules: Vec<String>,
+    // Asks to keep the plasmoid out of cyclenext. Hyprland has no rule for
+    // that: cyclenext only skips windows that can't take focus, which
+    // non-focusable plasmoids already can't, so this just warns otherwise.
+    #[serde(default)]
+    no_cycle: bool,
+    // Places the plasmoid by its anchor within a reference rect, then moves
+    // it by the offset. Takes over from anchor, margin and the cursor.
+    #[serde(default)]
+    position: Option<Position>,
+    // How the applet's SNI item is recognized: by an id ending in
+    // plasmawindowed_<plasmoid>, or by its Title matching `title`, for
+    // applets that name their item differently.
+    #[serde(default)]
+    sni_match: SniMatch,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "kebab-case")]
+enum SniMatch {
+    #[default]
+    Id,
+    Title,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+struct Position {
+    anchor: Anchor,
+    offset: (i64, i64),
+    relative_to: RelativeTo,
+}
+
+impl Default for Position {
+    fn default() -> Self {
+        Position {
+            anchor: Anchor::Center,
+            offset: (0, 0),
+            relative_to: RelativeTo::default(),
+        }
+    }
+}
+
+// The rect a `position` is anchored in. The cursor is a point, so anchoring
+// there puts that corner or edge of the plasmoid at the cursor.
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+enum RelativeTo {
+    #[default]
+    Monitor,
+    Cursor,
+    // The cursor without one.
+    FocusedWindow,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "kebab-case")]
+enum Mode {
+    #[default]
+    Float,
+    Special,
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+enum CloseAction {
+    #[default]
+    Close,
+    Kill,
+    // Keeps the window alive past the right edge of every monitor.
+    MinimizeOffscreen,
+    // A dispatcher and its arguments; `{window}` expands to the window.
+    Custom(String),
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+enum SizeMode {
+    #[default]
+    Fixed,
+    Auto,
+    // The size Plasma last saved for the applet, or width/height without one.
+    Remembered,
+}
+
+impl Plasmoid {
+    fn takes_focus(&self) -> bool {
+        self.focusable && self.focus_on_show
+    }
+
+    fn requested_size(&self) -> (u32, u32) {
+        let remembered = match self.size {
+            SizeMode::Remembered => remembered_size(&self.plasmoid),
+            SizeMode::Fixed | SizeMode::Auto => None,
+        };
+        remembered.unwrap_or((self.width, self.height))
+    }
+
+    // Special workspaces don't size their windows, and auto and remembered
+    // sizes can do without one.
+    fn missing_size(&self) -> bool {
+        self.mode == Mode::Float
+            && self.size == SizeMode::Fixed
+            && (self.width == 0 || self.height == 0)
+    }
+
+    // The requested size, times the monitor's scale with scale_with_monitor.
+    // Auto-sized plasmoids only use it as a first guess, so they aren't scaled
+    // beyond that.
+    fn size_on(&self, mon: Option<&Monitor>) -> (u32, u32) {
+        let (width, height) = self.requested_size();
+        match mon.filter(|_| self.scale_with_monitor) {
+            Some(mon) if mon.scale.is_finite() && mon.scale > 0.0 => {
+                let scale = mon.scale as f64;
+                (
+                    (width as f64 * scale) as u32,
+                    (height as f64 * scale) as u32,
+                )
+            }
+            _ => (width, height),
+        }
+    }
+}
+
+// Plasma keeps an applet's popupWidth/popupHeight somewhere under the group
+// of the applet in its appletsrc, e.g. [Containments][1][Applets][5][General].
+fn remembered_size(applet: &str) -> Option<(u32, u32)> {
+    let config = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(format!("{}/.config", env::var("HOME").unwrap())));
+    [
+        "plasmawindowed-appletsrc",
+        "plasma-org.kde.plasma.desktop-appletsrc",
+    ]
+    .iter()
+    .find_map(|file| {
+        let text = fs::read_to_string(config.join(file)).ok()?;
+        applet_size(&text, applet)
+    })
+}
+
+fn applet_size(text: &str, applet: &str) -> Option<(u32, u32)> {
+    let mut entries = Vec::new();
+    let mut group = "";
+    for line in text.lines().map(str::trim) {
+        if line.starts_with('[') {
+            group = line;
+        } else if let Some((key, value)) = line.split_once('=') {
+            entries.push((group, key.trim(), value.trim()));
+        }
+    }
+    entries
+        .iter()
+        .filter(|(_, key, value)| *key == "plugin" && *value == applet)
+        .find_map(|(applet_group, ..)| {
+            let nested = format!("{applet_group}[");
+            let setting = |name: &str| {
+                entries
+                    .iter()
+                    .filter(|(group, ..)| group == applet_group || group.starts_with(&nested))
+                    .find(|(_, key, _)| *key == name)
+                    .and_then(|(.., value)| value.parse().ok())
+            };
+            Some((setting("popupWidth")?, setting("popupHeight")?))
+        })
+}
+
+fn one_or_many<'de, D: serde::Deserializer<'de>>(de: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+    let titles = match OneOrMany::deserialize(de)? {
+        OneOrMany::One(title) => vec![title],
+        OneOrMany::Many(titles) => titles,
+    };
+    if titles.is_empty() {
+        return Err(serde::de::Error::custom("title list is empty"));
+    }
+    Ok(titles)
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_window_gap() -> i64 {
+    PADDING
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+enum Anchor {
+    TopLeft,
+    Top,
+    TopRight,
+    Left,
+    Center,
+    Right,
+    BottomLeft,
+    Bottom,
+    BottomRight,
+    // Beside the focused window, or at the cursor without one.
+    FocusedWindow,
+    // Centered over the focused window, or at the cursor without one.
+    OverFocused,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "kebab-case")]
+enum Side {
+    Left,
+    #[default]
+    Right,
+    Above,
+    Below,
+}
+
+#[derive(Clone, Copy)]
+enum Align {
+    Start,
+    Center,
+    End,
+}
+
+impl Anchor {
+    // How the anchor lines the window up with the monitor, if it does.
+    fn align(self) -> Option<(Align, Align)> {
+        use Align::*;
+        Some(match self {
+            Anchor::TopLeft => (Start, Start),
+            Anchor::Top => (Center, Start),
+            Anchor::TopRight => (End, Start),
+            Anchor::Left => (Start, Center),
+            Anchor::Center => (Center, Center),
+            Anchor::Right => (End, Center),
+            Anchor::BottomLeft => (Start, End),
+            Anchor::Bottom => (Center, End),
+            Anchor::BottomRight => (End, End),
+            Anchor::FocusedWindow | Anchor::OverFocused => return None,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+struct Margin {
+    top: i64,
+    right: i64,
+    bottom: i64,
+    left: i64,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct Animation {
+    fade_out_ms: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+struct Startup {
+    delay_ms: u64,
+    // How long to wait for the tray watcher before warming up anyway.
+    watcher_timeout_ms: u64,
+    // Ignores workspace changes for this long once the daemon is up, as
+    // Hyprland restoring a session at login switches workspaces a lot.
+    workspace_grace_ms: u64,
+}
+
+impl Default for Startup {
+    fn default() -> Self {
+        Startup {
+            delay_ms: 0,
+            watcher_timeout_ms: 10000,
+            workspace_grace_ms: 0,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct Warmup {
+    // Opens warm-up windows transparent, unanimated and on a special
+    // workspace nobody looks at, so nothing flashes on screen.
+    stealth: bool,
+}
+
+// A freshly spawned applet may show its window before its tray item is
+// registered, so a miss is retried a few times before spawning another one.
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+struct SniLookup {
+    retries: u32,
+    retry_interval_ms: u64,
+}
+
+impl Default for SniLookup {
+    fn default() -> Self {
+        SniLookup {
+            retries: 2,
+            retry_interval_ms: 100,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct MetricsSettings {
+    enabled: bool,
+    // Serves the counters in Prometheus text format on 127.0.0.1:<port>.
+    port: Option<u16>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+struct Settings {
+    ignore_special_workspaces: bool,
+    ignore_workspaces: Vec<String>,
+    friendly_titles: Vec<String>,
+    friendly_classes: Vec<String>,
+    // Reopens the plasmoids that were open when the daemon last stopped.
+    restore_session: bool,
+    on_any_show: Option<String>,
+    on_any_hide: Option<String>,
+    animation: Animation,
+    startup: Startup,
+    warmup: Warmup,
+    sni_lookup: SniLookup,
+    metrics: MetricsSettings,
+    max_open: Option<usize>,
+    manage_follow_mouse: bool,
+    // Manages follow-mouse with a window rule for the other windows rather
+    // than the global keyword, falling back to the keyword if Hyprland
+    // rejects the rule.
+    scoped_follow_mouse: bool,
+    manage_float_switch: bool,
+    manage_no_warps: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            ignore_special_workspaces: false,
+            ignore_workspaces: Vec::new(),
+            friendly_titles: Vec::new(),
+            friendly_classes: Vec::new(),
+            restore_session: false,
+            on_any_show: None,
+            on_any_hide: None,
+            animation: Animation::default(),
+            startup: Startup::default(),
+            warmup: Warmup::default(),
+            sni_lookup: SniLookup::default(),
+            metrics: MetricsSettings::default(),
+            max_open: None,
+            manage_follow_mouse: true,
+            scoped_follow_mouse: false,
+            manage_float_switch: true,
+            manage_no_warps: true,
+        }
+    }
+}
+
+// Plasmoids keep the order they're defined in, across files in load order;
+// an entry overridden by a later file keeps its original position.
+#[derive(Serialize, Deserialize)]
+struct Config {
+    #[serde(default)]
+    settings: Settings,
+    #[serde(flatten)]
+    plasmoids: IndexMap<String, Plasmoid>,
+}
+
+#[derive(Default)]
+struct State {
+    focus_holders: HashSet<String>,
+    windows: HashMap<String, Address>,
+    // Names of open plasmoids, least recently shown first.
+    shown: Vec<String>,
+    // Position of the last plasmoid shown by `cycle`, keyed by its name list.
+    cycles: HashMap<Vec<String>, usize>,
+    // Last SNI item (destination, path) seen for each applet id.
+    sni_items: HashMap<String, (String, String)>,
+    watcher_up: Option<bool>,
+    last_toggle: HashMap<String, Instant>,
+    // Set by `auto-hide off`; plasmoids then only close on explicit commands.
+    auto_hide_paused: bool,
+    // Set by `demo-mode on`: no auto-hiding and no cursor nudges, for
+    // recordings and presentations.
+    demo_mode: bool,
+    metrics: Metrics,
+    // Plasmoids shown while there were no monitors to place them on.
+    unplaced: HashSet<String>,
+    // Plasmoids waiting to be hidden by `peek`, with the serial of the peek.
+    peeks: HashMap<String, u64>,
+    next_peek: u64,
+    // Plasmoids shown with `--sticky`, which auto-hiding leaves alone.
+    sticky: HashSet<String>,
+    // Plasmoids with a running idle timer, with the serial of the timer.
+    idle_timers: HashMap<String, u64>,
+    next_idle: u64,
+    // The instances of mirrored plasmoids besides the one in `windows`.
+    mirrors: HashMap<String, Vec<Address>>,
+    // The plasmoid that had focus last, if focus is still on a plasmoid.
+    last_active: Option<String>,
+    // When each open plasmoid was last shown, for min_visible_ms.
+    shown_at: HashMap<String, Instant>,
+    // Set once Hyprland rejects `movecursor`, so nudging isn't retried.
+    no_movecursor: bool,
+}
+
+// Counters since the daemon started, served by `metrics`.
+#[derive(Default, Serialize)]
+struct Metrics {
+    shows: u64,
+    hides: u64,
+    activations: u64,
+    spawns: u64,
+    wait_timeouts: u64,
+    show_ms_total: u64,
+    opens: BTreeMap<String, u64>,
+}
+
+impl Metrics {
+    fn prometheus(&self) -> String {
+        let mut out = String::new();
+        for (metric, help, value) in [
+            ("shows_total", "Plasmoids shown.", self.shows),
+            ("hides_total", "Plasmoids hidden.", self.hides),
+            (
+                "activations_total",
+                "Shows that activated an existing SNI item.",
+                self.activations,
+            ),
+            (
+                "spawns_total",
+                "Shows that spawned plasmawindowed.",
+                self.spawns,
+            ),
+            (
+                "wait_timeouts_total",
+                "Shows whose window never appeared.",
+                self.wait_timeouts,
+            ),
+        ] {
+            writeln!(out, "# HELP hypr_plasmoid_{metric} {help}").ok();
+            writeln!(out, "# TYPE hypr_plasmoid_{metric} counter").ok();
+            writeln!(out, "hypr_plasmoid_{metric} {value}").ok();
+        }
+        writeln!(
+            out,
+            "# HELP hypr_plasmoid_show_seconds Time from show request to window."
+        )
+        .ok();
+        writeln!(out, "# TYPE hypr_plasmoid_show_seconds summary").ok();
+        writeln!(
+            out,
+            "hypr_plasmoid_show_seconds_sum {}",
+            self.show_ms_total as f64 / 1000.0
+        )
+        .ok();
+        writeln!(out, "hypr_plasmoid_show_seconds_count {}", self.shows).ok();
+        writeln!(out, "# HELP hypr_plasmoid_opens_total Shows per plasmoid.").ok();
+        writeln!(out, "# TYPE hypr_plasmoid_opens_total counter").ok();
+        for (name, count) in &self.opens {
+            let name = name.replace('\\', "\\\\").replace('"', "\\\"");
+            writeln!(
+                out,
+                "hypr_plasmoid_opens_total{{plasmoid=\"{name}\"}} {count}"
+            )
+            .ok();
+        }
+        out
+    }
+}
+
+struct App {
+    cfg: Config,
+    profile: Option<String>,
+    // Only missing in headless mode without a session bus.
+    conn: Option<Connection>,
+    state: RefCell<State>,
+}
+
+impl App {
+    fn plasmoid(&self, name: &str) -> &Plasmoid {
+        self.cfg.plasmoids.get(name).expect("unknown plasmoid")
+    }
+
+    // Prefers the window we last showed for `name` so that another window with
+    // the same title is never targeted, falling back to title matching for
+    // windows we didn't open ourselves.
+    fn window<'a>(&self, clients: &'a [Client], name: &str) -> Option<&'a Client> {
+        let tracked = self.state.borrow().windows.get(name).cloned();
+        tracked
+            .and_then(|address| clients.iter().find(|c| c.address == address))
+            .or_else(|| find_matching_client(clients, self.plasmoid(name)))
+    }
+
+    // Like `window`, but only if the window is actually on screen rather than
+    // alive and parked somewhere offscreen.
+    fn shown<'a>(&self, snap: &'a Snapshot, name: &str) -> Option<&'a Client> {
+        let mode = self.plasmoid(name).mode;
+        self.window(&snap.clients, name).filter(|c| match mode {
+            Mode::Float => is_onscreen(c, &snap.monitors),
+            Mode::Special => special_shown(&snap.monitors, name),
+        })
+    }
+
+    fn track(&self, name: &str, address: &Address) {
+        let mut state = self.state.borrow_mut();
+        state.windows.insert(name.into(), address.clone());
+        state.shown.retain(|n| n != name);
+        state.shown.push(name.into());
+    }
+
+    fn untrack(&self, name: &str) {
+        let mut state = self.state.borrow_mut();
+        state.windows.remove(name);
+        state.shown.retain(|n| n != name);
+        state.unplaced.remove(name);
+        state.peeks.remove(name);
+        state.sticky.remove(name);
+        state.idle_timers.remove(name);
+        state.mirrors.remove(name);
+        state.shown_at.remove(name);
+    }
+
+    fn close_mirrors(&self, name: &str) {
+        let mirrors = self.state.borrow_mut().mirrors.remove(name);
+        for address in mirrors.into_iter().flatten() {
+            dispatch(DispatchType::CloseWindow(WindowIdentifier::Address(
+                address,
+            )))
+            .ok();
+        }
+    }
+
+    fn tracked_name(&self, address: &Address) -> Option<String> {
+        self.state
+            .borrow()
+            .windows
+            .iter()
+            .find(|(_, tracked)| *tracked == address)
+            .map(|(name, _)| name.clone())
+    }
+
+    fn window_closed(&self, address: &Address) {
+        let closed: Vec<_> = {
+            let state = self.state.borrow();
+            state
+                .windows
+                .iter()
+                .filter(|(name, tracked)| {
+                    *tracked == address
+                        || state
+                            .mirrors
+                            .get(*name)
+                            .is_some_and(|m| m.contains(address))
+                })
+                .map(|(name, _)| name.clone())
+                .collect()
+        };
+        for name in closed {
+            // Closing one instance of a mirrored plasmoid closes the rest.
+            let primary = self.state.borrow().windows.get(&name).cloned();
+            if self.state.borrow().mirrors.contains_key(&name)
+                && let Some(primary) = primary.filter(|primary| primary != address)
+            {
+                dispatch(DispatchType::CloseWindow(WindowIdentifier::Address(
+                    primary,
+                )))
+                .ok();
+            }
+            self.close_mirrors(&name);
+            self.untrack(&name);
+            self.release_focus(&name);
+        }
+    }
+
+    // Hides the least recently shown plasmoids until `incoming` fits under
+    // the max_open cap.
+    async fn evict_for(&self, clients: &[Client], incoming: &str) {
+        let Some(max_open) = self.cfg.settings.max_open else {
+            return;
+        };
+        loop {
+            let oldest = {
+                let state = self.state.borrow();
+                let others: Vec<_> = state.shown.iter().filter(|n| *n != incoming).collect();
+                if others.len() < max_open {
+                    return;
+                }
+                others[0].clone()
+            };
+            debug!("evicting {oldest} to stay within max_open = {max_open}");
+            hide(self, clients, &oldest).await;
+            self.untrack(&oldest);
+        }
+    }
+
+    fn auto_hiding(&self) -> bool {
+        let state = self.state.borrow();
+        !state.auto_hide_paused && !state.demo_mode
+    }
+
+    // Sticky plasmoids, and ones shown less than min_visible_ms ago, are left
+    // alone by auto-hiding.
+    fn auto_hide_exempt(&self, name: &str) -> bool {
+        let p = self.plasmoid(name);
+        let state = self.state.borrow();
+        let min_visible = Duration::from_millis(p.min_visible_ms);
+        p.sticky
+            || state.sticky.contains(name)
+            || state
+                .shown_at
+                .get(name)
+                .is_some_and(|at| at.elapsed() < min_visible)
+    }
+
+    fn record_show(&self, name: &str, took: Duration) {
+        let mut state = self.state.borrow_mut();
+        state.shown_at.insert(name.into(), Instant::now());
+        let metrics = &mut state.metrics;
+        metrics.shows += 1;
+        metrics.show_ms_total += took.as_millis() as u64;
+        *metrics.opens.entry(name.into()).or_default() += 1;
+    }
+
+    fn acquire_focus(&self, name: &str) {
+        self.state.borrow_mut().focus_holders.insert(name.into());
+        set_focus_mode(&self.cfg, true);
+    }
+
+    // Focus mode stays on until the last plasmoid holding it is hidden, so
+    // closing one non-exclusive plasmoid doesn't break focus for the others.
+    fn release_focus(&self, name: &str) {
+        let mut state = self.state.borrow_mut();
+        state.focus_holders.remove(name);
+        if state.focus_holders.is_empty() {
+            set_focus_mode(&self.cfg, false);
+        }
+    }
+
+    // Sticky plasmoids stay open when focus leaves them, but shouldn't keep
+    // focus mode on for the rest of the desktop; focusing one takes it back.
+    fn release_sticky_focus(&self) {
+        let mut state = self.state.borrow_mut();
+        let State {
+            focus_holders,
+            sticky,
+            ..
+        } = &mut *state;
+        focus_holders.retain(|name| !self.plasmoid(name).sticky && !sticky.contains(name));
+    }
 }
 
-type Config = HashMap<String, Plasmoid>;
+const WATCHER_SERVICE: &str = "org.kde.StatusNotifierWatcher";
+const WATCHER_WAIT_MS: u64 = 1000;
+const ACTIVATE_ATTEMPTS: u32 = 3;
+const MONITOR_ATTEMPTS: u32 = 5;
+const IDLE_POLL_MS: u64 = 250;
+const CONFIG_WAIT_MS: u64 = 5000;
+const SHUTDOWN_WAIT_MS: u64 = 3000;
 
 #[proxy(
     interface = "org.kde.StatusNotifierWatcher",
@@ -41,241 +846,4247 @@ trait Watcher {
 trait Sni {
     #[zbus(property)]
     fn id(&self) -> zbus::Result<String>;
+    #[zbus(property)]
+    fn title(&self) -> zbus::Result<String>;
     fn activate(&self, x: i32, y: i32) -> zbus::Result<()>;
 }
 
-fn load_config() -> Config {
-    let path = format!("{}/.config/hypr/plasmoids.json", env::var("HOME").unwrap());
-    serde_json::from_str(&fs::read_to_string(&path).expect("config not found"))
-        .expect("invalid json")
+// A profile's plasmoids.<profile>.json takes the place of plasmoids.json;
+// plasmoids.d is shared by all profiles and, as always, overrides it.
+fn config_files(profile: Option<&str>) -> Vec<PathBuf> {
+    let dir = PathBuf::from(format!("{}/.config/hypr", env::var("HOME").unwrap()));
+    let main = match profile {
+        Some(profile) => format!("plasmoids.{profile}.json"),
+        None => "plasmoids.json".into(),
+    };
+    let mut files: Vec<_> = Some(dir.join(main))
+        .filter(|path| path.exists())
+        .into_iter()
+        .collect();
+    if let Ok(entries) = fs::read_dir(dir.join("plasmoids.d")) {
+        let mut extra: Vec<_> = entries
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .collect();
+        extra.sort();
+        files.extend(extra);
+    }
+    files
 }
 
-fn rule_prop(name: &str, prop: &str) -> String {
-    format!("windowrule[{name}]:{prop}")
+// Parsed as JSON5, so config files can have comments and trailing commas.
+fn read_config_file(path: &Path) -> Result<Value, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+    json5::from_str(&text).map_err(|e| format!("{}: invalid json: {e}", path.display()))
 }
 
-fn find_matching_title(title_pattern: &str) -> Option<String> {
-    let re = Regex::new(&format!("^({title_pattern})$")).ok()?;
-    Clients::get()
-        .ok()?
-        .iter()
-        .find(|w| re.is_match(&w.title))
-        .map(|w| w.title.clone())
-}
+fn try_load_config(profile: Option<&str>) -> Result<Config, String> {
+    let files = config_files(profile);
+    if files.is_empty() {
+        return Err(NO_PLASMOIDS.into());
+    }
 
-fn set_focus_mode(show: bool) {
-    Keyword::set("input:follow_mouse", if show { "2" } else { "1" }).ok();
-    Keyword::set(
-        "input:float_switch_override_focus",
-        if show { "0" } else { "1" },
-    )
-    .ok();
-    if show {
-        Keyword::set("cursor:no_warps", "1").ok();
+    let mut merged = serde_json::Map::new();
+    for path in files {
+        let Value::Object(entries) = read_config_file(&path)? else {
+            return Err(format!("{}: expected a JSON object", path.display()));
+        };
+        for (key, value) in entries {
+            if merged.insert(key.clone(), value).is_some() {
+                eprintln!("warning: {} overrides earlier {key}", path.display());
+            }
+        }
+    }
+    apply_defaults(&mut merged)?;
+    for (key, value) in merged.iter_mut().filter(|(key, _)| *key != "settings") {
+        expand_env_values(value).map_err(|e| format!("{key}: {e}"))?;
+    }
+    let cfg = serde_json::from_value(Value::Object(merged))
+        .map_err(|e| format!("invalid config: {e}"))?;
+    check_config(&cfg)?;
+    for (name, p) in &cfg.plasmoids {
+        if p.no_cycle && p.focusable {
+            eprintln!(
+                "warning: {name}: Hyprland can't keep focusable windows out of cyclenext, \
+                 no_cycle only holds with focusable: false"
+            );
+        }
     }
+    Ok(cfg)
 }
 
-fn set_window_rules(name: &str, p: &Plasmoid) {
-    let Ok(cursor) = CursorPosition::get() else {
-        return;
-    };
-    let Ok(monitors) = Monitors::get() else {
-        return;
-    };
-    let Some(mon) = monitors.iter().find(|m| m.focused) else {
-        return;
+// Fills in every plasmoid entry from the top-level `defaults` block; whatever
+// an entry sets itself wins, down to single fields of objects like `margin`.
+fn apply_defaults(merged: &mut serde_json::Map<String, Value>) -> Result<(), String> {
+    let Some(defaults) = merged.remove("defaults") else {
+        return Ok(());
     };
-
-    let mon_x = mon.x as i64;
-    let mon_width = (mon.width as f64 / mon.scale as f64) as i64;
-    let x = (cursor.x - PADDING).clamp(
-        mon_x + PADDING,
-        mon_x + mon_width - p.width as i64 - PADDING,
-    );
-    let y = (cursor.y - PADDING).max(mon.y as i64 + mon.reserved.1 as i64 + PADDING);
-
-    let rule_name = format!("hypr-plasmoid-{name}");
-    Keyword::set(&rule_prop(&rule_name, "match:title"), format!("^({})$", p.title)).ok();
-    Keyword::set(&rule_prop(&rule_name, "float"), "yes").ok();
-    Keyword::set(&rule_prop(&rule_name, "size"), format!("{} {}", p.width, p.height)).ok();
-    Keyword::set(&rule_prop(&rule_name, "move"), format!("{x} {y}")).ok();
-    Keyword::set(&rule_prop(&rule_name, "enable"), "1").ok();
+    if !defaults.is_object() {
+        return Err("defaults: expected a JSON object".into());
+    }
+    for (_, entry) in merged.iter_mut().filter(|(key, _)| *key != "settings") {
+        fill_from(entry, &defaults);
+    }
+    Ok(())
 }
 
-fn spawn_plasmoid(p: &Plasmoid) {
-    Command::new("plasmawindowed")
-        .args(["--statusnotifier", &p.plasmoid])
-        .stdin(Stdio::null())
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .spawn()
-        .ok();
+fn fill_from(value: &mut Value, defaults: &Value) {
+    let (Value::Object(value), Value::Object(defaults)) = (value, defaults) else {
+        return;
+    };
+    for (key, default) in defaults {
+        match value.get_mut(key) {
+            Some(own) => fill_from(own, default),
+            None => {
+                value.insert(key.clone(), default.clone());
+            }
+        }
+    }
 }
 
-async fn find_sni(conn: &Connection, plasmoid: &str) -> Option<(String, String)> {
-    let watcher = WatcherProxy::new(conn).await.ok()?;
-    let suffix = format!("plasmawindowed_{plasmoid}");
-    for item in watcher.registered_status_notifier_items().await.ok()? {
-        let (dest, path) = item.split_once('/')?;
-        let sni = SniProxy::builder(conn)
-            .destination(dest)
-            .ok()?
-            .path(format!("/{path}"))
-            .ok()?
-            .build()
-            .await
-            .ok()?;
-        if sni.id().await.ok()?.ends_with(&suffix) {
-            return Some((dest.into(), format!("/{path}")));
+fn check_config(cfg: &Config) -> Result<(), String> {
+    for (name, p) in &cfg.plasmoids {
+        if p.plasmoid.is_empty() && p.command.is_none() {
+            return Err(format!("{name}: needs a plasmoid or a command"));
+        }
+        if p.mode == Mode::Special && p.mirror {
+            return Err(format!("{name}: mirror doesn't work with mode special"));
+        }
+        if let Some(position) = &p.position
+            && position.anchor.align().is_none()
+        {
+            return Err(format!(
+                "{name}: position.anchor can't refer to a window, use relative_to"
+            ));
+        }
+        if p.missing_size() {
+            return Err(format!(
+                "{name}: needs a width and height, or size auto or remembered"
+            ));
         }
     }
-    None
+    Ok(())
 }
 
-async fn wait_for_window(title_pattern: &str, timeout_ms: u64) -> Option<String> {
-    let start = std::time::Instant::now();
-    while start.elapsed().as_millis() < timeout_ms as u128 {
-        if let Some(title) = find_matching_title(title_pattern) {
-            return Some(title);
+// Expands `$VAR` and `${VAR}` from the environment; `$$` is a literal `$`, and
+// a `$` not followed by a variable name (like a regex anchor) is left alone.
+fn expand_env(text: &str) -> Result<String, String> {
+    let re = Regex::new(r"\$(?:\$|\{([A-Za-z_][A-Za-z0-9_]*)\}|([A-Za-z_][A-Za-z0-9_]*))")
+        .expect("valid regex");
+    let mut out = String::new();
+    let mut last = 0;
+    for caps in re.captures_iter(text) {
+        let whole = caps.get(0).expect("group 0 always matches");
+        out.push_str(&text[last..whole.start()]);
+        match caps.get(1).or(caps.get(2)) {
+            Some(var) => out.push_str(
+                &env::var(var.as_str())
+                    .map_err(|_| format!("references unset variable ${}", var.as_str()))?,
+            ),
+            None => out.push('$'),
         }
-        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+        last = whole.end();
     }
-    None
+    out.push_str(&text[last..]);
+    Ok(out)
 }
 
-fn hide(title_pattern: &str) {
-    if let Some(title) = find_matching_title(title_pattern) {
-        Dispatch::call(DispatchType::CloseWindow(WindowIdentifier::Title(&title))).ok();
+fn expand_env_values(value: &mut Value) -> Result<(), String> {
+    match value {
+        Value::String(text) => *text = expand_env(text)?,
+        Value::Array(items) => items.iter_mut().try_for_each(expand_env_values)?,
+        Value::Object(fields) => fields.values_mut().try_for_each(expand_env_values)?,
+        _ => {}
     }
+    Ok(())
 }
 
-fn hide_all(cfg: &Config, except: Option<&str>) {
-    for (name, p) in cfg {
-        if Some(name.as_str()) != except {
-            hide(&p.title);
-        }
-    }
+fn load_config(profile: Option<&str>) -> Config {
+    try_load_config(profile).unwrap_or_else(|e| {
+        eprintln!("{e}");
+        std::process::exit(1);
+    })
 }
 
-fn nudge_cursor() {
-    if let Ok(c) = CursorPosition::get() {
-        Dispatch::call(DispatchType::Custom(
-            "movecursor",
-            &format!("{} {}", c.x + 1, c.y),
-        ))
-        .ok();
-        Dispatch::call(DispatchType::Custom(
-            "movecursor",
-            &format!("{} {}", c.x, c.y),
-        ))
-        .ok();
-    }
+// With HYPR_PLASMOID_HEADLESS set nothing reaches Hyprland: a Recorder stands
+// in for it, printing keywords and dispatches instead of sending them and
+// answering queries with one synthetic monitor and the cursor at its centre.
+// Nothing is launched and the session bus is optional. This runs the whole
+// binary without a Hyprland session, e.g. in CI, showing what it would do.
+fn headless() -> bool {
+    env::var_os("HYPR_PLASMOID_HEADLESS").is_some()
 }
 
-async fn show(conn: &Connection, cfg: &Config, name: &str) -> zbus::Result<()> {
-    let p = cfg.get(name).expect("unknown plasmoid");
+// Everything sent to or asked of Hyprland goes through the current
+// Compositor, so headless runs and tests can swap in a Recorder.
+type PendingDispatch = Pin<Box<dyn Future<Output = hyprland::Result<()>>>>;
 
-    if let Some(title) = find_matching_title(&p.title) {
-        Dispatch::call(DispatchType::FocusWindow(WindowIdentifier::Title(&title))).ok();
-        hide_all(cfg, Some(name));
-        return Ok(());
+trait Compositor {
+    fn keyword(&self, key: &str, value: &str) -> hyprland::Result<()>;
+    fn dispatch(&self, dispatch: DispatchType) -> hyprland::Result<()>;
+    fn dispatch_async(&self, dispatcher: String, args: String) -> PendingDispatch;
+    fn clients(&self) -> Vec<Client>;
+    fn monitors(&self) -> Vec<Monitor>;
+    fn cursor(&self) -> Option<CursorPosition>;
+    fn active_window(&self) -> Option<Address>;
+    fn active_workspace(&self) -> Option<WorkspaceId>;
+    fn version(&self) -> hyprland::Result<String>;
+}
+
+struct Hyprland;
+
+impl Compositor for Hyprland {
+    fn keyword(&self, key: &str, value: &str) -> hyprland::Result<()> {
+        Keyword::set(key, value)
     }
 
-    set_focus_mode(true);
-    hide_all(cfg, Some(name));
-    set_window_rules(name, p);
+    fn dispatch(&self, dispatch: DispatchType) -> hyprland::Result<()> {
+        Dispatch::call(dispatch)
+    }
 
-    if let Some((dest, path)) = find_sni(conn, &p.plasmoid).await {
-        SniProxy::builder(conn)
-            .destination(dest.as_str())?
-            .path(path.as_str())?
-            .build()
-            .await?
-            .activate(0, 0)
-            .await?;
-    } else {
-        spawn_plasmoid(p);
+    fn dispatch_async(&self, dispatcher: String, args: String) -> PendingDispatch {
+        Box::pin(
+            async move { Dispatch::call_async(DispatchType::Custom(&dispatcher, &args)).await },
+        )
     }
 
-    if let Some(title) = wait_for_window(&p.title, 500).await {
-        Dispatch::call(DispatchType::FocusWindow(WindowIdentifier::Title(&title))).ok();
+    fn clients(&self) -> Vec<Client> {
+        Clients::get().map(|c| c.to_vec()).unwrap_or_default()
     }
-    Ok(())
-}
 
-async fn toggle(conn: &Connection, cfg: &Config, name: &str) -> zbus::Result<()> {
-    let p = cfg.get(name).expect("unknown plasmoid");
-    if find_matching_title(&p.title).is_some() {
-        hide(&p.title);
-        set_focus_mode(false);
-    } else {
-        show(conn, cfg, name).await?;
+    fn monitors(&self) -> Vec<Monitor> {
+        Monitors::get().map(|m| m.to_vec()).unwrap_or_default()
+    }
+
+    fn cursor(&self) -> Option<CursorPosition> {
+        CursorPosition::get().ok()
+    }
+
+    fn active_window(&self) -> Option<Address> {
+        Client::get_active().ok().flatten().map(|c| c.address)
+    }
+
+    fn active_workspace(&self) -> Option<WorkspaceId> {
+        Workspace::get_active().ok().map(|w| w.id)
+    }
+
+    fn version(&self) -> hyprland::Result<String> {
+        Version::get().map(|v| v.version.unwrap_or(v.tag))
     }
-    nudge_cursor();
-    Ok(())
 }
 
-fn config_cmd(cfg: &Config, name: &str) {
-    let p = cfg.get(name).expect("unknown plasmoid");
-    Dispatch::call(DispatchType::Exec(&format!(
-        "plasmawindowed --config {}",
-        p.plasmoid
-    )))
-    .ok();
+// Records what would have been sent, one line per keyword or dispatch, and
+// answers queries from whatever it was set up with.
+#[derive(Default)]
+struct Recorder {
+    calls: RefCell<Vec<String>>,
+    clients: Vec<Client>,
+    monitors: Vec<Monitor>,
+    cursor: Option<CursorPosition>,
+    active: Option<Address>,
+    echo: bool,
 }
 
-async fn warm_up(cfg: &Config) {
-    for (_name, p) in cfg {
-        spawn_plasmoid(p);
-        if let Some(title) = wait_for_window(&p.title, 2000).await {
-            Dispatch::call(DispatchType::CloseWindow(WindowIdentifier::Title(&title))).ok();
+impl Recorder {
+    fn headless() -> Self {
+        let monitor = serde_json::from_value(serde_json::json!({
+            "id": 0, "name": "HEADLESS-1", "description": "headless", "width": 1920,
+            "height": 1080, "refreshRate": 60.0, "x": 0, "y": 0,
+            "activeWorkspace": { "id": 1, "name": "1" },
+            "specialWorkspace": { "id": 0, "name": "" },
+            "reserved": [0, 0, 0, 0], "scale": 1.0, "transform": 0, "focused": true,
+            "dpmsStatus": true, "vrr": false, "disabled": false,
+        }))
+        .expect("synthetic monitor");
+        Recorder {
+            monitors: vec![monitor],
+            cursor: Some(CursorPosition { x: 960, y: 540 }),
+            echo: true,
+            ..Recorder::default()
+        }
+    }
+
+    fn record(&self, call: String) {
+        if self.echo {
+            eprintln!("headless: {call}");
         }
+        self.calls.borrow_mut().push(call);
     }
-    Keyword::set(&rule_prop("plasmoid-warmup", "enable"), "0").ok();
 }
 
-async fn daemon(cfg: Arc<Config>) {
-    warm_up(&cfg).await;
+impl Compositor for Recorder {
+    fn keyword(&self, key: &str, value: &str) -> hyprland::Result<()> {
+        self.record(format!("keyword {key} {value}"));
+        Ok(())
+    }
 
-    let patterns: Vec<_> = cfg
-        .values()
-        .filter_map(|p| Regex::new(&format!("^({})$", p.title)).ok())
-        .collect();
-    let mut listener = EventListener::new();
+    fn dispatch(&self, dispatch: DispatchType) -> hyprland::Result<()> {
+        self.record(match dispatch {
+            DispatchType::Custom(name, args) => format!("{name} {args}"),
+            dispatch => format!("{dispatch:?}"),
+        });
+        Ok(())
+    }
 
-    let cfg2 = cfg.clone();
-    listener.add_workspace_changed_handler(move |_| hide_all(&cfg2, None));
+    fn dispatch_async(&self, dispatcher: String, args: String) -> PendingDispatch {
+        self.record(format!("{dispatcher} {args}"));
+        Box::pin(std::future::ready(Ok(())))
+    }
 
-    let cfg3 = cfg.clone();
-    listener.add_active_window_changed_handler(move |data| {
-        let dominated = data
-            .as_ref()
-            .is_some_and(|d| patterns.iter().any(|re| re.is_match(&d.title)));
-        set_focus_mode(dominated);
-        if !dominated {
-            hide_all(&cfg3, None);
-        }
+    fn clients(&self) -> Vec<Client> {
+        self.clients.clone()
+    }
+
+    fn monitors(&self) -> Vec<Monitor> {
+        self.monitors.clone()
+    }
+
+    fn cursor(&self) -> Option<CursorPosition> {
+        self.cursor
+    }
+
+    fn active_window(&self) -> Option<Address> {
+        self.active.clone()
+    }
+
+    fn active_workspace(&self) -> Option<WorkspaceId> {
+        let focused = self.monitors.iter().find(|m| m.focused);
+        focused.map(|m| m.active_workspace.id)
+    }
+
+    fn version(&self) -> hyprland::Result<String> {
+        Ok("none (headless)".into())
+    }
+}
+
+thread_local! {
+    static COMPOSITOR: RefCell<Rc<dyn Compositor>> = RefCell::new(if headless() {
+        Rc::new(Recorder::headless())
+    } else {
+        Rc::new(Hyprland)
     });
+}
 
-    listener
-        .start_listener_async()
-        .await
-        .expect("Failed to start listener");
+fn compositor() -> Rc<dyn Compositor> {
+    COMPOSITOR.with_borrow(Rc::clone)
 }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() -> zbus::Result<()> {
-    let cfg = Arc::new(load_config());
-    let conn = Connection::session().await?;
-    let args: Vec<_> = env::args().skip(1).collect();
-    let name = args.get(1).map(|s| s.as_str());
+fn set_keyword(key: impl ToString, value: impl Into<OptionValue>) -> hyprland::Result<()> {
+    compositor().keyword(&key.to_string(), &value.into().to_string())
+}
+
+fn dispatch(dispatch: DispatchType) -> hyprland::Result<()> {
+    compositor().dispatch(dispatch)
+}
 
-    match args.first().map(|s| s.as_str()) {
-        Some("toggle") => toggle(&conn, &cfg, name.expect("missing plasmoid name")).await?,
-        Some("config") => config_cmd(&cfg, name.expect("missing plasmoid name")),
-        Some("hide-all") => hide_all(&cfg, None),
-        Some("daemon") => daemon(cfg).await,
-        _ => eprintln!("usage: hypr-plasmoid <toggle|config|hide-all|daemon> [name]"),
+fn rule_prop(name: &str, prop: &str) -> String {
+    format!("windowrule[{name}]:{prop}")
+}
+
+fn title_rule(p: &Plasmoid) -> String {
+    let titles = p.title.join("|");
+    if p.title_prefix {
+        format!("^({titles}).*$")
+    } else {
+        format!("^({titles})$")
+    }
+}
+
+struct Snapshot {
+    clients: Vec<Client>,
+    monitors: Vec<Monitor>,
+    cursor: Option<CursorPosition>,
+}
+
+impl Snapshot {
+    fn take() -> Self {
+        Snapshot {
+            clients: query_clients(),
+            monitors: query_monitors(),
+            cursor: compositor().cursor(),
+        }
+    }
+
+    // Early in a session Hyprland can briefly report no monitors at all, so
+    // a plasmoid about to be shown waits a little for one.
+    async fn take_for_show() -> Self {
+        let mut snap = Snapshot::take();
+        for _ in 1..MONITOR_ATTEMPTS {
+            if !snap.monitors.is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            snap.monitors = query_monitors();
+        }
+        snap
+    }
+
+    fn focused_monitor(&self) -> Option<&Monitor> {
+        self.monitors.iter().find(|m| m.focused)
+    }
+}
+
+fn query_monitors() -> Vec<Monitor> {
+    compositor().monitors()
+}
+
+fn query_clients() -> Vec<Client> {
+    compositor().clients()
+}
+
+fn client_matcher(p: &Plasmoid) -> Option<impl Fn(&Client) -> bool> {
+    let title = Regex::new(&title_rule(p)).ok()?;
+    let class = match &p.class {
+        Some(class) => Some(Regex::new(&format!("^({class})$")).ok()?),
+        None => None,
+    };
+    Some(move |c: &Client| {
+        title.is_match(&c.title) && class.as_ref().is_none_or(|re| re.is_match(&c.class))
+    })
+}
+
+fn find_matching_client<'a>(clients: &'a [Client], p: &Plasmoid) -> Option<&'a Client> {
+    let matches = client_matcher(p)?;
+    clients.iter().find(|c| matches(c))
+}
+
+// Instead of input:follow_mouse, which applies to every window, a rule that
+// only keeps the windows other than plasmoids from taking focus on hover.
+fn set_focus_rule(cfg: &Config, show: bool) -> bool {
+    let rule_name = "hypr-plasmoid-focus";
+    // Defined on hiding too, so that hiding falls back to the keyword exactly
+    // when showing did.
+    let titles: Vec<_> = cfg.plasmoids.values().map(title_rule).collect();
+    let title = format!("negative:({})", titles.join("|"));
+    if set_keyword(rule_prop(rule_name, "match:title"), title).is_err()
+        || set_keyword(rule_prop(rule_name, "no_follow_mouse"), "yes").is_err()
+    {
+        return false;
+    }
+    set_keyword(rule_prop(rule_name, "enable"), if show { "1" } else { "0" }).is_ok()
+}
+
+fn set_focus_mode(cfg: &Config, show: bool) {
+    let settings = &cfg.settings;
+    if settings.manage_follow_mouse && !(settings.scoped_follow_mouse && set_focus_rule(cfg, show))
+    {
+        set_keyword("input:follow_mouse", if show { "2" } else { "1" }).ok();
+    }
+    if settings.manage_float_switch {
+        set_keyword(
+            "input:float_switch_override_focus",
+            if show { "0" } else { "1" },
+        )
+        .ok();
+    }
+    if show && settings.manage_no_warps {
+        set_keyword("cursor:no_warps", "1").ok();
+    }
+}
+
+fn run_position_command(
+    cmd: &str,
+    name: &str,
+    p: &Plasmoid,
+    mon: &Monitor,
+    cursor: &CursorPosition,
+    (width, height): (u32, u32),
+) -> Option<(i64, i64, u32, u32)> {
+    let (left, top, right, bottom) = mon.reserved;
+    let output = Command::new("sh")
+        .args(["-c", cmd])
+        .env("PLASMOID_NAME", name)
+        .env("PLASMOID_APPLET", &p.plasmoid)
+        .env("PLASMOID_WIDTH", width.to_string())
+        .env("PLASMOID_HEIGHT", height.to_string())
+        .env("CURSOR_X", cursor.x.to_string())
+        .env("CURSOR_Y", cursor.y.to_string())
+        .env("MONITOR_NAME", &mon.name)
+        .env("MONITOR_X", mon.x.to_string())
+        .env("MONITOR_Y", mon.y.to_string())
+        .env("MONITOR_WIDTH", mon.width.to_string())
+        .env("MONITOR_HEIGHT", mon.height.to_string())
+        .env("MONITOR_SCALE", mon.scale.to_string())
+        .env("MONITOR_RESERVED", format!("{left} {top} {right} {bottom}"))
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        eprintln!("position_command for {name} exited with {}", output.status);
+        return None;
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let fields: Vec<_> = stdout.split_whitespace().collect();
+    let parsed = match fields[..] {
+        [x, y] => (x.parse().ok(), y.parse().ok(), Some(width), Some(height)),
+        [x, y, w, h] => (
+            x.parse().ok(),
+            y.parse().ok(),
+            w.parse().ok(),
+            h.parse().ok(),
+        ),
+        _ => (None, None, None, None),
+    };
+    let (Some(x), Some(y), Some(w), Some(h)) = parsed else {
+        eprintln!(
+            "position_command for {name} printed {stdout:?}, expected \"x y [width height]\""
+        );
+        return None;
+    };
+    Some((x, y, w, h))
+}
+
+#[derive(Clone, Copy)]
+struct Rect {
+    x: i64,
+    y: i64,
+    width: i64,
+    height: i64,
+}
+
+impl Rect {
+    fn right(&self) -> i64 {
+        self.x + self.width
+    }
+
+    fn bottom(&self) -> i64 {
+        self.y + self.height
+    }
+}
+
+// Monitor coordinates come from i32/u16 fields, so only user-supplied margins
+// and the cursor can get anywhere near overflowing an i64; those go through
+// saturating arithmetic below.
+fn monitor_rect(mon: &Monitor) -> Rect {
+    let scale = if mon.scale.is_finite() && mon.scale > 0.0 {
+        mon.scale as f64
+    } else {
+        1.0
+    };
+    Rect {
+        x: mon.x as i64,
+        y: mon.y as i64,
+        width: (mon.width as f64 / scale) as i64,
+        height: (mon.height as f64 / scale) as i64,
+    }
+}
+
+fn usable_area(mon: &Monitor) -> Rect {
+    let (left, top, right, bottom) = mon.reserved;
+    let full = monitor_rect(mon);
+    Rect {
+        x: full.x + left as i64,
+        y: full.y + top as i64,
+        width: (full.width - left as i64 - right as i64).max(0),
+        height: (full.height - top as i64 - bottom as i64).max(0),
+    }
+}
+
+fn align_axis(align: Align, start: i64, len: i64, size: i64, before: i64, after: i64) -> i64 {
+    match align {
+        Align::Start => start.saturating_add(before),
+        Align::Center => {
+            let free = len
+                .saturating_sub(before)
+                .saturating_sub(after)
+                .saturating_sub(size);
+            start.saturating_add(before).saturating_add(free / 2)
+        }
+        Align::End => start
+            .saturating_add(len)
+            .saturating_sub(after)
+            .saturating_sub(size),
+    }
+}
+
+// Opens the window just past the cursor, flipping to the other side of it when
+// there isn't room before `hi`, like a context menu near a screen edge.
+// Unless clamped, the flipped window can still stick out past `lo`.
+fn place_axis(cursor: i64, size: i64, lo: i64, hi: i64, clamp: bool) -> i64 {
+    let start = if cursor.saturating_sub(PADDING).saturating_add(size) > hi {
+        cursor.saturating_add(PADDING).saturating_sub(size)
+    } else {
+        cursor.saturating_sub(PADDING)
+    };
+    if !clamp {
+        return start;
+    }
+    start.clamp(lo, (hi - size).max(lo))
+}
+
+// The window a plasmoid anchored to the focused window goes beside: the most
+// recently focused one shown on `mon`, other than the plasmoid itself.
+fn focused_window(snap: &Snapshot, mon: &Monitor, p: &Plasmoid) -> Option<Rect> {
+    let is_plasmoid = client_matcher(p)?;
+    let window = snap
+        .clients
+        .iter()
+        .filter(|c| c.monitor == Some(mon.id) && c.workspace.id == mon.active_workspace.id)
+        .filter(|c| c.focus_history_id >= 0 && !is_plasmoid(c))
+        .min_by_key(|c| c.focus_history_id)?;
+    Some(Rect {
+        x: window.at.0 as i64,
+        y: window.at.1 as i64,
+        width: window.size.0 as i64,
+        height: window.size.1 as i64,
+    })
+}
+
+fn beside(window: &Rect, side: Side, gap: i64, width: i64, height: i64) -> (i64, i64) {
+    match side {
+        Side::Left => (window.x.saturating_sub(gap).saturating_sub(width), window.y),
+        Side::Right => (window.right().saturating_add(gap), window.y),
+        Side::Above => (
+            window.x,
+            window.y.saturating_sub(gap).saturating_sub(height),
+        ),
+        Side::Below => (window.x, window.bottom().saturating_add(gap)),
+    }
+}
+
+fn centered_on(window: &Rect, width: i64, height: i64) -> (i64, i64) {
+    (
+        window
+            .x
+            .saturating_add(window.width.saturating_sub(width) / 2),
+        window
+            .y
+            .saturating_add(window.height.saturating_sub(height) / 2),
+    )
+}
+
+fn reference_rect(
+    snap: &Snapshot,
+    mon: &Monitor,
+    p: &Plasmoid,
+    relative_to: RelativeTo,
+    area: &Rect,
+) -> Rect {
+    let at_cursor = snap.cursor.as_ref().map_or(*area, |c| Rect {
+        x: c.x,
+        y: c.y,
+        width: 0,
+        height: 0,
+    });
+    match relative_to {
+        RelativeTo::Monitor => *area,
+        RelativeTo::Cursor => at_cursor,
+        RelativeTo::FocusedWindow => focused_window(snap, mon, p).unwrap_or(at_cursor),
+    }
+}
+
+fn place_in(reference: &Rect, position: &Position, width: i64, height: i64) -> (i64, i64) {
+    let (h, v) = position
+        .anchor
+        .align()
+        .unwrap_or((Align::Center, Align::Center));
+    let (dx, dy) = position.offset;
+    (
+        align_axis(h, reference.x, reference.width, width, 0, 0).saturating_add(dx),
+        align_axis(v, reference.y, reference.height, height, 0, 0).saturating_add(dy),
+    )
+}
+
+fn window_geometry(
+    snap: &Snapshot,
+    name: &str,
+    p: &Plasmoid,
+    size: (u32, u32),
+) -> Option<(i64, i64, u32, u32)> {
+    geometry_on(snap, snap.focused_monitor()?, name, p, size)
+}
+
+fn geometry_on(
+    snap: &Snapshot,
+    mon: &Monitor,
+    name: &str,
+    p: &Plasmoid,
+    size: (u32, u32),
+) -> Option<(i64, i64, u32, u32)> {
+    let cursor = snap.cursor.as_ref()?;
+
+    match &p.position_command {
+        Some(cmd) => run_position_command(cmd, name, p, mon, cursor, size),
+        None => {
+            let area = usable_area(mon);
+            // Hyprland shrinks floating windows that don't fit on their own,
+            // which throws off the placement below.
+            let max = (
+                (area.width - 2 * PADDING).max(1),
+                (area.height - 2 * PADDING).max(1),
+            );
+            let (width, height) = ((size.0 as i64).min(max.0), (size.1 as i64).min(max.1));
+            let window = match p.anchor {
+                Some(Anchor::FocusedWindow | Anchor::OverFocused) => focused_window(snap, mon, p),
+                _ => None,
+            };
+            let (x, y) = match (window, p.anchor.and_then(Anchor::align)) {
+                _ if let Some(position) = &p.position => {
+                    let reference = reference_rect(snap, mon, p, position.relative_to, &area);
+                    place_in(&reference, position, width, height)
+                }
+                (Some(window), _) if p.anchor == Some(Anchor::OverFocused) => {
+                    centered_on(&window, width, height)
+                }
+                (Some(window), _) => beside(&window, p.window_side, p.window_gap, width, height),
+                (None, Some((h, v))) => {
+                    let m = &p.margin;
+                    (
+                        align_axis(h, area.x, area.width, width, m.left, m.right),
+                        align_axis(v, area.y, area.height, height, m.top, m.bottom),
+                    )
+                }
+                (None, None) => (
+                    place_axis(
+                        cursor.x,
+                        width,
+                        area.x + PADDING,
+                        area.right() - PADDING,
+                        p.clamp,
+                    ),
+                    place_axis(
+                        cursor.y,
+                        height,
+                        area.y + PADDING,
+                        area.bottom() - PADDING,
+                        p.clamp,
+                    ),
+                ),
+            };
+            if !p.clamp {
+                return Some((x, y, width as u32, height as u32));
+            }
+            // Margins can push an anchored window anywhere; keep it on the monitor.
+            let x = x.clamp(area.x, (area.right() - width).max(area.x));
+            let y = y.clamp(area.y, (area.bottom() - height).max(area.y));
+            Some((x, y, width as u32, height as u32))
+        }
+    }
+}
+
+// Returns where the window is placed, so the rest of a show can go by that
+// instead of working it out (and running position_command) again.
+fn set_window_rules(snap: &Snapshot, name: &str, p: &Plasmoid) -> Option<(i64, i64, u32, u32)> {
+    let rule_name = format!("hypr-plasmoid-{name}");
+    if p.mode == Mode::Special {
+        set_match_rules(&rule_name, p);
+        set_keyword(
+            rule_prop(&rule_name, "workspace"),
+            format!("special:{} silent", special_workspace(name)),
+        )
+        .ok();
+        set_focus_rules(&rule_name, p);
+        set_extra_rules(name, &rule_name, p);
+        set_keyword(rule_prop(&rule_name, "enable"), "1").ok();
+        return None;
+    }
+
+    let mon = snap.focused_monitor()?;
+    let size = p.size_on(Some(mon));
+    let (x, y, width, height) = geometry_on(snap, mon, name, p, size)?;
+    if p.position_command.is_none() && (width, height) != size {
+        eprintln!(
+            "{name}: {}x{} doesn't fit on {}, clamping to {width}x{height}",
+            size.0, size.1, mon.name
+        );
+    }
+
+    set_match_rules(&rule_name, p);
+    set_keyword(rule_prop(&rule_name, "float"), "yes").ok();
+    if p.size != SizeMode::Auto {
+        set_keyword(rule_prop(&rule_name, "size"), format!("{width} {height}")).ok();
+    }
+    set_keyword(rule_prop(&rule_name, "move"), format!("{x} {y}")).ok();
+    set_focus_rules(&rule_name, p);
+    // Hyprland dims everything behind the window (see decoration:dim_around);
+    // clicking the dimmed area unfocuses the plasmoid, which the daemon
+    // already treats as a dismissal.
+    if p.scrim && p.exclusive {
+        set_keyword(rule_prop(&rule_name, "dim_around"), "yes").ok();
+    }
+    if let Some(workspace) = &p.workspace {
+        set_keyword(
+            rule_prop(&rule_name, "workspace"),
+            format!("{workspace} silent"),
+        )
+        .ok();
+    }
+    set_extra_rules(name, &rule_name, p);
+    set_keyword(rule_prop(&rule_name, "enable"), "1").ok();
+    Some((x, y, width, height))
+}
+
+// A prop without a value is a flag, like "pin".
+fn extra_rule(entry: &str) -> (&str, &str) {
+    let entry = entry.trim();
+    match entry.split_once(char::is_whitespace) {
+        Some((prop, value)) => (prop, value.trim_start()),
+        None => (entry, "yes"),
+    }
+}
+
+fn set_extra_rules(name: &str, rule_name: &str, p: &Plasmoid) {
+    for entry in &p.extra_rules {
+        let (prop, value) = extra_rule(entry);
+        if let Err(e) = set_keyword(rule_prop(rule_name, prop), value) {
+            eprintln!("{name}: extra rule \"{entry}\" was rejected: {e}");
+        }
+    }
+}
+
+// Turns the plasmoids' rules off, extra rules included, so nothing applies to
+// windows opened while the daemon isn't running.
+fn disable_window_rules(cfg: &Config) {
+    for name in cfg.plasmoids.keys() {
+        let rule_name = format!("hypr-plasmoid-{name}");
+        set_keyword(rule_prop(&rule_name, "enable"), "0").ok();
+    }
+}
+
+fn set_match_rules(rule_name: &str, p: &Plasmoid) {
+    set_keyword(rule_prop(rule_name, "match:title"), title_rule(p)).ok();
+    if let Some(class) = &p.class {
+        set_keyword(rule_prop(rule_name, "match:class"), format!("^({class})$")).ok();
+    }
+}
+
+fn set_focus_rules(rule_name: &str, p: &Plasmoid) {
+    if !p.focusable {
+        set_keyword(rule_prop(rule_name, "no_focus"), "yes").ok();
+    } else if !p.focus_on_show {
+        set_keyword(rule_prop(rule_name, "no_initial_focus"), "yes").ok();
+    }
+}
+
+// The special workspace a `mode: "special"` plasmoid lives on, without the
+// "special:" prefix that togglespecialworkspace doesn't take.
+fn special_workspace(name: &str) -> String {
+    format!("hypr-plasmoid-{name}")
+}
+
+fn special_shown(monitors: &[Monitor], name: &str) -> bool {
+    let workspace = format!("special:{}", special_workspace(name));
+    monitors
+        .iter()
+        .any(|m| m.special_workspace.name == workspace)
+}
+
+fn toggle_special(name: &str) {
+    let workspace = special_workspace(name);
+    if let Err(e) = dispatch(DispatchType::Custom("togglespecialworkspace", &workspace)) {
+        eprintln!("toggling special workspace {workspace} failed: {e}");
+    }
+}
+
+fn move_window(address: &Address, x: i64, y: i64) {
+    dispatch(DispatchType::Custom(
+        "movewindowpixel",
+        &format!("exact {x} {y},address:{address}"),
+    ))
+    .ok();
+}
+
+// Auto-sized plasmoids were placed using their configured size as a guess, so
+// place them again now that Hyprland knows how big they actually are.
+fn reanchor(snap: &Snapshot, name: &str, p: &Plasmoid, address: &Address) {
+    let clients = query_clients();
+    let Some(client) = clients.iter().find(|c| c.address == *address) else {
+        return;
+    };
+    let size = (client.size.0.max(0) as u32, client.size.1.max(0) as u32);
+    if let Some((x, y, ..)) = window_geometry(snap, name, p, size) {
+        move_window(&client.address, x, y);
+    }
+}
+
+// Hyprland doesn't report a window's min/max size, but it holds floating
+// windows to them, so a window that settled at a size other than the one the
+// rule asked for can't take the configured size without breaking its layout.
+// Keep the size rule at what it settled at and place the window by that.
+fn honor_size_limits(
+    snap: &Snapshot,
+    name: &str,
+    p: &Plasmoid,
+    placed: Option<(i64, i64, u32, u32)>,
+    address: &Address,
+) {
+    let Some((.., width, height)) = placed else {
+        return;
+    };
+    let clients = query_clients();
+    let Some(client) = clients.iter().find(|c| c.address == *address) else {
+        return;
+    };
+    let settled = (client.size.0.max(0) as u32, client.size.1.max(0) as u32);
+    if settled == (width, height) || settled.0 == 0 || settled.1 == 0 {
+        return;
+    }
+    eprintln!(
+        "{name}: adjusted {width}x{height} to {}x{} to respect the window's size limits",
+        settled.0, settled.1
+    );
+    set_keyword(
+        rule_prop(&format!("hypr-plasmoid-{name}"), "size"),
+        format!("{} {}", settled.0, settled.1),
+    )
+    .ok();
+    if let Some((x, y, ..)) = window_geometry(snap, name, p, settled) {
+        move_window(address, x, y);
+    }
+}
+
+fn on_any_monitor(client: &Client, monitors: &[Monitor]) -> bool {
+    let (x, y) = (client.at.0 as i64, client.at.1 as i64);
+    let (width, height) = (client.size.0 as i64, client.size.1 as i64);
+    monitors
+        .iter()
+        .map(monitor_rect)
+        .any(|m| x < m.right() && x + width > m.x && y < m.bottom() && y + height > m.y)
+}
+
+// Any part showing counts, so an unclamped window peeking in from past the
+// edge is shown, while one parked beyond every monitor isn't.
+fn is_onscreen(client: &Client, monitors: &[Monitor]) -> bool {
+    // Without monitor info there's nothing to compare against.
+    monitors.is_empty() || on_any_monitor(client, monitors)
+}
+
+// Places plasmoids that were shown before any monitor existed.
+fn place_unplaced(app: &App) {
+    let unplaced = std::mem::take(&mut app.state.borrow_mut().unplaced);
+    if unplaced.is_empty() {
+        return;
+    }
+    let snap = Snapshot::take();
+    for name in &unplaced {
+        let p = app.plasmoid(name);
+        let placed = set_window_rules(&snap, name, p);
+        let Some(client) = app.window(&snap.clients, name) else {
+            continue;
+        };
+        let placed = match p.size {
+            SizeMode::Auto => {
+                let size = (client.size.0.max(0) as u32, client.size.1.max(0) as u32);
+                window_geometry(&snap, name, p, size)
+            }
+            SizeMode::Fixed | SizeMode::Remembered => placed,
+        };
+        let Some((x, y, width, height)) = placed else {
+            continue;
+        };
+        if p.size != SizeMode::Auto {
+            dispatch(DispatchType::Custom(
+                "resizewindowpixel",
+                &format!("exact {width} {height},address:{}", client.address),
+            ))
+            .ok();
+        }
+        move_window(&client.address, x, y);
+    }
+}
+
+// Brings plasmoids stranded on a monitor that went away back to the focused
+// one, or hides them if there's nowhere left to put them.
+async fn rescue_stranded(app: &App) {
+    let snap = Snapshot::take();
+    for (name, p) in &app.cfg.plasmoids {
+        let Some(client) = app.window(&snap.clients, name) else {
+            continue;
+        };
+        if on_any_monitor(client, &snap.monitors) {
+            continue;
+        }
+        let size = (client.size.0.max(0) as u32, client.size.1.max(0) as u32);
+        match window_geometry(&snap, name, p, size) {
+            Some((x, y, ..)) => move_window(&client.address, x, y),
+            None => hide(app, &snap.clients, name).await,
+        }
+    }
+}
+
+fn spawn_plasmoid(p: &Plasmoid) -> Option<Child> {
+    if headless() {
+        let what = p.command.as_deref().unwrap_or(&p.plasmoid);
+        eprintln!("headless: launch {what}");
+        return None;
+    }
+    let mut cmd = match &p.command {
+        Some(command) => {
+            let mut cmd = Command::new("sh");
+            cmd.args(["-c", command]);
+            cmd
+        }
+        None => {
+            let mut cmd = Command::new("plasmawindowed");
+            cmd.args(["--statusnotifier", &p.plasmoid]);
+            cmd
+        }
+    };
+    cmd.stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    // In its own process group, signals meant for the daemon (like ^C in the
+    // terminal it was started from) don't take the plasmoid down with it.
+    if p.detach {
+        cmd.process_group(0);
+    }
+    cmd.spawn()
+        .inspect_err(|e| {
+            let what = p.command.as_deref().unwrap_or(&p.plasmoid);
+            eprintln!("failed to launch {what}: {e}");
+        })
+        .ok()
+}
+
+// Whether `pid` is `ancestor` or one of its descendants, e.g. an app started
+// by the shell running a `command`.
+fn descends_from(pid: i32, ancestor: u32) -> bool {
+    let Ok(mut pid) = u32::try_from(pid) else {
+        return false;
+    };
+    while pid != ancestor {
+        // The parent is the second field after the parenthesized command name.
+        let parent = fs::read_to_string(format!("/proc/{pid}/stat"))
+            .ok()
+            .and_then(|stat| {
+                let (_, rest) = stat.rsplit_once(')')?;
+                rest.split_whitespace().nth(1)?.parse().ok()
+            });
+        match parent {
+            Some(parent) if parent > 1 => pid = parent,
+            _ => return false,
+        }
+    }
+    true
+}
+
+// The session bus unless --bus-address names another; zbus already honors
+// DBUS_SESSION_BUS_ADDRESS for the session bus.
+async fn connect(address: Option<&str>) -> zbus::Result<Connection> {
+    match address {
+        Some(address) => zbus::connection::Builder::address(address)?.build().await,
+        None => Connection::session().await,
+    }
+}
+
+async fn has_owner(conn: &Connection, name: &str) -> bool {
+    let Ok(name) = BusName::try_from(name) else {
+        return false;
+    };
+    match zbus::fdo::DBusProxy::new(conn).await {
+        Ok(dbus) => dbus.name_has_owner(name).await.unwrap_or(false),
+        Err(_) => false,
+    }
+}
+
+async fn wait_for_watcher(app: &App, timeout_ms: u64) -> bool {
+    let Some(conn) = &app.conn else {
+        return false;
+    };
+    let start = Instant::now();
+    let up = loop {
+        if has_owner(conn, WATCHER_SERVICE).await {
+            break true;
+        }
+        if start.elapsed().as_millis() >= timeout_ms as u128 {
+            break false;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    };
+    let previous = app.state.borrow_mut().watcher_up.replace(up);
+    match (previous, up) {
+        (Some(false), true) => eprintln!("{WATCHER_SERVICE} is available again"),
+        (Some(true) | None, false) => eprintln!("{WATCHER_SERVICE} is unavailable"),
+        _ => {}
+    }
+    up
+}
+
+// The (destination, path) of every item the watcher knows about.
+async fn registered_items(conn: &Connection) -> Option<Vec<(String, String)>> {
+    let watcher = WatcherProxy::new(conn).await.ok()?;
+    let items = watcher.registered_status_notifier_items().await.ok()?;
+    Some(
+        items
+            .iter()
+            .filter_map(|item| item.split_once('/'))
+            .map(|(dest, path)| (dest.into(), format!("/{path}")))
+            .collect(),
+    )
+}
+
+async fn sni_item<'a>(conn: &Connection, dest: &'a str, path: &'a str) -> Option<SniProxy<'a>> {
+    SniProxy::builder(conn)
+        .destination(dest)
+        .ok()?
+        .path(path)
+        .ok()?
+        .build()
+        .await
+        .ok()
+}
+
+async fn sni_id(conn: &Connection, dest: &str, path: &str) -> Option<String> {
+    sni_item(conn, dest, path).await?.id().await.ok()
+}
+
+async fn sni_title(conn: &Connection, dest: &str, path: &str) -> Option<String> {
+    sni_item(conn, dest, path).await?.title().await.ok()
+}
+
+async fn scan_sni(conn: &Connection, p: &Plasmoid) -> Option<(String, String)> {
+    let suffix = format!("plasmawindowed_{}", p.plasmoid);
+    let title = Regex::new(&title_rule(p)).ok()?;
+    for (dest, path) in registered_items(conn).await? {
+        let found = match p.sni_match {
+            SniMatch::Id => sni_id(conn, &dest, &path)
+                .await
+                .is_some_and(|id| id.ends_with(&suffix)),
+            SniMatch::Title => sni_title(conn, &dest, &path)
+                .await
+                .is_some_and(|t| title.is_match(&t)),
+        };
+        if found {
+            return Some((dest, path));
+        }
+    }
+    None
+}
+
+// While the tray watcher is restarting, fall back to the last item we saw for
+// this applet as long as its process is still on the bus, rather than
+// spawning a duplicate that would show up once the watcher returns.
+async fn find_sni(app: &App, p: &Plasmoid) -> Option<(String, String)> {
+    let plasmoid = p.plasmoid.as_str();
+    let conn = app.conn.as_ref()?;
+    if wait_for_watcher(app, WATCHER_WAIT_MS).await {
+        let lookup = &app.cfg.settings.sni_lookup;
+        let mut found = scan_sni(conn, p).await;
+        for _ in 0..lookup.retries {
+            if found.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(lookup.retry_interval_ms)).await;
+            found = scan_sni(conn, p).await;
+        }
+        let mut state = app.state.borrow_mut();
+        match &found {
+            Some(item) => state.sni_items.insert(plasmoid.into(), item.clone()),
+            None => state.sni_items.remove(plasmoid),
+        };
+        return found;
+    }
+    let known = app.state.borrow().sni_items.get(plasmoid).cloned()?;
+    has_owner(conn, &known.0).await.then_some(known)
+}
+
+// With the process we just spawned, only its own windows count, so another
+// instance with the same title isn't mistaken for it. That only holds while it
+// runs though: plasmawindowed may hand off to an instance that's already up
+// and exit, leaving title matching.
+async fn wait_for_window(p: &Plasmoid, spawned: Option<Child>, timeout_ms: u64) -> Option<Address> {
+    let matches = client_matcher(p)?;
+    let Some(child) = spawned else {
+        return wait_for_client(matches, timeout_ms).await;
+    };
+    let pid = child.id();
+    let child = RefCell::new(child);
+    let exited = || child.borrow_mut().try_wait().is_ok_and(|s| s.is_some());
+    wait_for_client(
+        |c: &Client| matches(c) && (descends_from(c.pid, pid) || exited()),
+        timeout_ms,
+    )
+    .await
+}
+
+async fn wait_for_title(re: &Regex, timeout_ms: u64) -> Option<Address> {
+    wait_for_client(|c: &Client| re.is_match(&c.title), timeout_ms).await
+}
+
+async fn wait_for_client(matches: impl Fn(&Client) -> bool, timeout_ms: u64) -> Option<Address> {
+    let start = Instant::now();
+    while start.elapsed().as_millis() < timeout_ms as u128 {
+        if let Some(client) = query_clients().iter().find(|c| matches(c)) {
+            return Some(client.address.clone());
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    None
+}
+
+// Runs a plasmoid's own dispatches and hook, then the global hook with
+// `{name}` expanded.
+fn run_hooks(name: &str, dispatches: &[String], own: Option<&str>, global: &Option<String>) {
+    for dispatch in dispatches {
+        if let Err(e) = dispatch_raw(dispatch) {
+            eprintln!("dispatch {dispatch:?} for {name} failed: {e}");
+        }
+    }
+    run_hook(name, own);
+    let global = global.as_ref().map(|cmd| cmd.replace("{name}", name));
+    run_hook(name, global.as_deref());
+}
+
+// A dispatcher and its arguments, as in `hyprctl dispatch`.
+fn dispatch_raw(cmd: &str) -> hyprland::Result<()> {
+    let (dispatcher, args) = cmd.split_once(' ').unwrap_or((cmd, ""));
+    dispatch(DispatchType::Custom(dispatcher, args))
+}
+
+fn run_hook(name: &str, cmd: Option<&str>) {
+    if let Some(cmd) = cmd
+        && let Err(e) = dispatch(DispatchType::Exec(cmd))
+    {
+        eprintln!("hook for {name} failed: {e}");
+    }
+}
+
+async fn hide(app: &App, clients: &[Client], name: &str) {
+    hide_many(app, clients, [name]).await;
+}
+
+// Hides several plasmoids from one client list. Their windows fade out
+// together, then are all closed by concurrent dispatches, and only once
+// those are done is the state updated.
+async fn hide_many(
+    app: &App,
+    clients: &[Client],
+    names: impl IntoIterator<Item = impl AsRef<str>>,
+) {
+    let closing: Vec<_> = names
+        .into_iter()
+        .filter_map(|name| closing(app, clients, name.as_ref()))
+        .collect();
+    let fade_ms = app.cfg.settings.animation.fade_out_ms;
+    if fade_ms > 0 && closing.iter().any(|c| c.fades) {
+        for c in closing.iter().filter(|c| c.fades) {
+            dispatch(DispatchType::Custom(
+                "setprop",
+                &format!("{} alpha 0", c.window),
+            ))
+            .ok();
+        }
+        tokio::time::sleep(Duration::from_millis(fade_ms)).await;
+    }
+    let mut dispatches = JoinSet::new();
+    for c in &closing {
+        let call = compositor().dispatch_async(c.dispatcher.clone(), c.args.clone());
+        let name = c.name.clone();
+        dispatches.spawn_local(async move { (name, call.await) });
+    }
+    while let Some(done) = dispatches.join_next().await {
+        if let Ok((name, Err(e))) = done {
+            eprintln!("hiding {name} failed: {e}");
+        }
+    }
+    for c in &closing {
+        hidden(app, c);
+    }
+}
+
+// How a plasmoid's window is put away, worked out before anything is sent.
+struct Closing {
+    name: String,
+    window: String,
+    dispatcher: String,
+    args: String,
+    // Parked windows and special workspaces stay open, just out of sight.
+    kept: bool,
+    fades: bool,
+}
+
+fn closing(app: &App, clients: &[Client], name: &str) -> Option<Closing> {
+    let p = app.plasmoid(name);
+    let client = app.window(clients, name)?;
+    if p.mode != Mode::Special {
+        app.close_mirrors(name);
+    }
+    let window = WindowIdentifier::Address(client.address.clone()).to_string();
+    let (dispatcher, args) = match &p.close_action {
+        // The window stays on its special workspace, which is just put away.
+        _ if p.mode == Mode::Special => {
+            if !special_shown(&query_monitors(), name) {
+                return None;
+            }
+            ("togglespecialworkspace".into(), special_workspace(name))
+        }
+        CloseAction::Close => ("closewindow".into(), window.clone()),
+        CloseAction::Kill => ("killwindow".into(), window.clone()),
+        CloseAction::MinimizeOffscreen => {
+            let monitors = query_monitors();
+            if !is_onscreen(client, &monitors) {
+                return None;
+            }
+            let x = monitors
+                .iter()
+                .map(|m| monitor_rect(m).right())
+                .max()
+                .unwrap_or(0);
+            let args = format!("exact {} 0,{window}", x + PADDING);
+            ("movewindowpixel".into(), args)
+        }
+        CloseAction::Custom(cmd) => {
+            let cmd = cmd.replace("{window}", &window);
+            let (dispatcher, args) = cmd.split_once(' ').unwrap_or((&cmd, ""));
+            (dispatcher.into(), args.into())
+        }
+    };
+    Some(Closing {
+        name: name.into(),
+        window,
+        dispatcher,
+        args,
+        kept: p.mode == Mode::Special || p.close_action == CloseAction::MinimizeOffscreen,
+        fades: fades_out(p),
+    })
+}
+
+// A parked window comes back as-is, so it mustn't be left transparent, and a
+// special workspace is put away whole.
+fn fades_out(p: &Plasmoid) -> bool {
+    p.mode == Mode::Float && p.close_action != CloseAction::MinimizeOffscreen
+}
+
+fn hidden(app: &App, closing: &Closing) {
+    let name = closing.name.as_str();
+    let p = app.plasmoid(name);
+    if closing.kept {
+        app.state.borrow_mut().shown.retain(|n| n != name);
+    } else {
+        app.untrack(name);
+    }
+    app.state.borrow_mut().metrics.hides += 1;
+    app.release_focus(name);
+    run_hooks(
+        name,
+        &p.on_hide_dispatch,
+        p.on_hide.as_deref(),
+        &app.cfg.settings.on_any_hide,
+    );
+}
+
+async fn hide_here(app: &App) {
+    let Some(workspace) = compositor().active_workspace() else {
+        return;
+    };
+    let clients = query_clients();
+    let here = app.cfg.plasmoids.keys().filter(|name| {
+        app.window(&clients, name)
+            .is_some_and(|c| c.workspace.id == workspace)
+    });
+    hide_many(app, &clients, here).await;
+}
+
+async fn hide_all(app: &App, clients: &[Client], except: Option<&str>) {
+    let names = app.cfg.plasmoids.keys();
+    let names = names.filter(|name| Some(name.as_str()) != except);
+    hide_many(app, clients, names).await;
+}
+
+// Hides plasmoids in response to focus or workspace changes, as opposed to
+// an explicit command, so sticky plasmoids stay.
+// Plasmoids pinned to a workspace live there, so changing workspaces is no
+// reason to hide them.
+async fn auto_hide_all(app: &App, workspace_changed: bool) {
+    let clients = query_clients();
+    let names = app.cfg.plasmoids.iter().filter_map(|(name, p)| {
+        let pinned = workspace_changed && p.workspace.is_some();
+        (!app.auto_hide_exempt(name) && !pinned).then_some(name)
+    });
+    hide_many(app, &clients, names).await;
+}
+
+async fn hide_unfocusable(app: &App) {
+    let clients = query_clients();
+    let names = app
+        .cfg
+        .plasmoids
+        .iter()
+        .filter(|(name, p)| !p.focusable && !app.auto_hide_exempt(name))
+        .map(|(name, _)| name);
+    hide_many(app, &clients, names).await;
+}
+
+// Hyprland versions without the `movecursor` dispatcher reject it, which
+// only costs the focus re-evaluation, so say so once and stop trying.
+fn nudge_cursor(app: &App, cursor: Option<&CursorPosition>) {
+    let state = app.state.borrow();
+    if state.demo_mode || state.no_movecursor {
+        return;
+    }
+    drop(state);
+    let Some(c) = cursor else {
+        return;
+    };
+    let move_cursor =
+        |x: i64, y: i64| dispatch(DispatchType::Custom("movecursor", &format!("{x} {y}")));
+    if let Err(e) = move_cursor(c.x + 1, c.y) {
+        let hyprland = compositor().version().unwrap_or_else(|_| "unknown".into());
+        eprintln!(
+            "not nudging the cursor, Hyprland {hyprland} rejected movecursor: {e}; \
+             focus may stay where the plasmoid was until the mouse moves"
+        );
+        app.state.borrow_mut().no_movecursor = true;
+        return;
+    }
+    move_cursor(c.x, c.y).ok();
+}
+
+async fn activate_sni(
+    conn: &Connection,
+    dest: &str,
+    path: &str,
+    (x, y): (i32, i32),
+) -> zbus::Result<()> {
+    SniProxy::builder(conn)
+        .destination(dest)?
+        .path(path)?
+        .build()
+        .await?
+        .activate(x, y)
+        .await
+}
+
+// Where to tell the applet it was clicked, which some applets use to place
+// their own popups: the top center of the window we're about to place when
+// anchored, otherwise the cursor.
+fn activate_point(
+    snap: &Snapshot,
+    p: &Plasmoid,
+    placed: Option<(i64, i64, u32, u32)>,
+) -> (i32, i32) {
+    if p.anchor.is_some()
+        && let Some((x, y, width, _)) = placed
+    {
+        return (saturate_i32(x + width as i64 / 2), saturate_i32(y));
+    }
+    snap.cursor
+        .as_ref()
+        .map_or((0, 0), |c| (saturate_i32(c.x), saturate_i32(c.y)))
+}
+
+fn saturate_i32(value: i64) -> i32 {
+    value.clamp(i32::MIN as i64, i32::MAX as i64) as i32
+}
+
+fn off_focused_monitor(snap: &Snapshot, client: &Client) -> bool {
+    snap.monitors
+        .iter()
+        .find(|m| m.focused)
+        .is_some_and(|m| client.monitor != Some(m.id))
+}
+
+async fn show(app: &App, snap: &Snapshot, name: &str) -> zbus::Result<()> {
+    let p = app.plasmoid(name);
+    if p.mirror {
+        show_mirrored(app, snap, name).await;
+        return Ok(());
+    }
+
+    if p.mode == Mode::Special
+        && let Some(client) = app.window(&snap.clients, name)
+    {
+        if p.exclusive {
+            hide_all(app, &snap.clients, Some(name)).await;
+        } else {
+            app.evict_for(&snap.clients, name).await;
+        }
+        app.track(name, &client.address);
+        let hidden = !special_shown(&snap.monitors, name);
+        if hidden {
+            toggle_special(name);
+        }
+        if p.takes_focus() {
+            dispatch(DispatchType::FocusWindow(WindowIdentifier::Address(
+                client.address.clone(),
+            )))
+            .ok();
+            app.acquire_focus(name);
+        }
+        if hidden {
+            app.record_show(name, Duration::ZERO);
+            run_hooks(
+                name,
+                &p.on_show_dispatch,
+                p.on_show.as_deref(),
+                &app.cfg.settings.on_any_show,
+            );
+        }
+        return Ok(());
+    }
+
+    if let Some(client) = app.window(&snap.clients, name) {
+        let parked = !is_onscreen(client, &snap.monitors);
+        if parked && !p.exclusive {
+            app.evict_for(&snap.clients, name).await;
+        }
+        app.track(name, &client.address);
+        let elsewhere = p.follow_to_cursor_monitor && off_focused_monitor(snap, client);
+        let reposition = p.reposition_on_reshow || elsewhere;
+        let placed = reposition
+            .then(|| set_window_rules(snap, name, p))
+            .flatten();
+        if parked || reposition {
+            let size = (client.size.0.max(0) as u32, client.size.1.max(0) as u32);
+            let placed = placed.filter(|&(.., width, height)| (width, height) == size);
+            if let Some((x, y, ..)) = placed.or_else(|| window_geometry(snap, name, p, size)) {
+                move_window(&client.address, x, y);
+            }
+        }
+        if p.takes_focus() {
+            dispatch(DispatchType::FocusWindow(WindowIdentifier::Address(
+                client.address.clone(),
+            )))
+            .ok();
+            app.acquire_focus(name);
+        }
+        if p.exclusive {
+            hide_all(app, &snap.clients, Some(name)).await;
+        }
+        if parked {
+            app.record_show(name, Duration::ZERO);
+            run_hooks(
+                name,
+                &p.on_show_dispatch,
+                p.on_show.as_deref(),
+                &app.cfg.settings.on_any_show,
+            );
+        }
+        return Ok(());
+    }
+
+    let start = Instant::now();
+    if p.takes_focus() {
+        app.acquire_focus(name);
+    }
+    if p.exclusive {
+        hide_all(app, &snap.clients, Some(name)).await;
+    } else {
+        app.evict_for(&snap.clients, name).await;
+    }
+    let placed = set_window_rules(snap, name, p);
+    if snap.monitors.is_empty() && p.mode == Mode::Float {
+        debug!("no monitors yet, placing {name} once one appears");
+        app.state.borrow_mut().unplaced.insert(name.into());
+    }
+
+    let mut activated = false;
+    let sni = match p.command {
+        Some(_) => None,
+        None => find_sni(app, p).await,
+    };
+    if let Some((dest, path)) = &sni
+        && let Some(conn) = &app.conn
+    {
+        let point = activate_point(snap, p, placed);
+        for attempt in 1..=ACTIVATE_ATTEMPTS {
+            match activate_sni(conn, dest, path, point).await {
+                Ok(()) => {
+                    activated = true;
+                    break;
+                }
+                Err(e) => {
+                    debug!("activating {dest}{path} failed (attempt {attempt}): {e}");
+                    if attempt < ACTIVATE_ATTEMPTS {
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                    }
+                }
+            }
+        }
+        if !activated {
+            // Don't keep offering an item that stopped answering.
+            app.state.borrow_mut().sni_items.remove(&p.plasmoid);
+        }
+    }
+    let spawned = if activated {
+        app.state.borrow_mut().metrics.activations += 1;
+        None
+    } else {
+        app.state.borrow_mut().metrics.spawns += 1;
+        spawn_plasmoid(p)
+    };
+    let launched = match (&sni, &spawned) {
+        (Some((dest, path)), _) if activated => format!("activated SNI item {dest}{path}"),
+        _ => {
+            let found = match (&p.command, &sni) {
+                (Some(_), _) => "not an applet".to_string(),
+                (None, Some((dest, path))) => format!("SNI item {dest}{path} didn't answer"),
+                (None, None) => "no SNI item".to_string(),
+            };
+            let what = p.command.as_deref().unwrap_or("plasmawindowed");
+            match &spawned {
+                Some(child) => format!("{found}, started {what} as pid {}", child.id()),
+                None if headless() => format!("{found}, headless so not starting {what}"),
+                None => format!("{found}, failed to start {what}"),
+            }
+        }
+    };
+
+    let Some(mut address) = wait_for_window(p, spawned, 500).await else {
+        app.state.borrow_mut().metrics.wait_timeouts += 1;
+        report_missing_window(name, p, &launched);
+        return Ok(());
+    };
+    // Some plasmoids map a splash window first; hold off until the real one.
+    if let Some(ready) = p.ready_title.as_deref()
+        && let Ok(re) = Regex::new(&format!("^({ready})$"))
+        && let Some(ready) = wait_for_title(&re, 2000).await
+    {
+        address = ready;
+    }
+    if p.ready_delay_ms > 0 {
+        tokio::time::sleep(Duration::from_millis(p.ready_delay_ms)).await;
+        if let Some(client) = find_matching_client(&query_clients(), p) {
+            address = client.address.clone();
+        }
+    }
+    app.track(name, &address);
+    app.record_show(name, start.elapsed());
+    match p.mode {
+        Mode::Float if p.size == SizeMode::Auto => reanchor(snap, name, p, &address),
+        Mode::Float => honor_size_limits(snap, name, p, placed, &address),
+        Mode::Special if !special_shown(&query_monitors(), name) => toggle_special(name),
+        _ => {}
+    }
+    // Focusing a window on another workspace would switch to it.
+    let stay_put = p.workspace.is_some() && !p.switch_to_workspace;
+    if let Some(workspace) = p.workspace.as_deref().filter(|_| p.switch_to_workspace) {
+        dispatch(DispatchType::Custom("workspace", workspace)).ok();
+    }
+    if p.takes_focus() && !stay_put {
+        dispatch(DispatchType::FocusWindow(WindowIdentifier::Address(
+            address,
+        )))
+        .ok();
+    }
+    run_hooks(
+        name,
+        &p.on_show_dispatch,
+        p.on_show.as_deref(),
+        &app.cfg.settings.on_any_show,
+    );
+    Ok(())
+}
+
+// Instances of a mirrored plasmoid all have the same title, so each is
+// spawned on its own, told apart by its process and moved to its monitor.
+async fn show_mirrored(app: &App, snap: &Snapshot, name: &str) {
+    let p = app.plasmoid(name);
+    if let Some(client) = app.window(&snap.clients, name) {
+        if is_onscreen(client, &snap.monitors) {
+            return;
+        }
+        // A parked instance would just be in the way.
+        dispatch(DispatchType::CloseWindow(WindowIdentifier::Address(
+            client.address.clone(),
+        )))
+        .ok();
+        app.untrack(name);
+    }
+    let start = Instant::now();
+    if p.exclusive {
+        hide_all(app, &snap.clients, Some(name)).await;
+    } else {
+        app.evict_for(&snap.clients, name).await;
+    }
+    set_window_rules(snap, name, p);
+
+    let mut placed = p.clone();
+    placed.anchor.get_or_insert(Anchor::Center);
+    let mut instances: Vec<(Address, bool)> = Vec::new();
+    for mon in &snap.monitors {
+        app.state.borrow_mut().metrics.spawns += 1;
+        let Some(address) = wait_for_window(p, spawn_plasmoid(p), 2000).await else {
+            app.state.borrow_mut().metrics.wait_timeouts += 1;
+            continue;
+        };
+        if instances.iter().any(|(a, _)| *a == address) {
+            continue;
+        }
+        if let Some((x, y, ..)) = geometry_on(snap, mon, name, &placed, p.size_on(Some(mon))) {
+            move_window(&address, x, y);
+        }
+        instances.push((address, mon.focused));
+    }
+    // The instance on the focused monitor stands in for all of them.
+    instances.sort_by_key(|(_, focused)| !focused);
+    let mut instances = instances.into_iter().map(|(address, _)| address);
+    let Some(primary) = instances.next() else {
+        return;
+    };
+    app.track(name, &primary);
+    app.state
+        .borrow_mut()
+        .mirrors
+        .insert(name.into(), instances.collect());
+    app.record_show(name, start.elapsed());
+    if p.takes_focus() {
+        app.acquire_focus(name);
+        dispatch(DispatchType::FocusWindow(WindowIdentifier::Address(
+            primary,
+        )))
+        .ok();
+    }
+    run_hooks(
+        name,
+        &p.on_show_dispatch,
+        p.on_show.as_deref(),
+        &app.cfg.settings.on_any_show,
+    );
+}
+
+// Shows what happened when a plasmoid's window never appeared, so spawning,
+// title matching and timing problems can be told apart.
+fn report_missing_window(name: &str, p: &Plasmoid, launched: &str) {
+    let windows: Vec<_> = query_clients()
+        .iter()
+        .map(|c| format!("{:?} ({})", c.title, c.class))
+        .collect();
+    eprintln!(
+        "{name}: no window matching {} appeared: {launched}; open windows: {}",
+        title_rule(p),
+        if windows.is_empty() {
+            "none".into()
+        } else {
+            windows.join(", ")
+        }
+    );
+}
+
+async fn toggle(app: &App, name: &str, sticky: bool) -> zbus::Result<()> {
+    let p = app.plasmoid(name);
+    if p.cooldown_ms > 0 {
+        let now = Instant::now();
+        let cooldown = Duration::from_millis(p.cooldown_ms);
+        let mut state = app.state.borrow_mut();
+        if state
+            .last_toggle
+            .get(name)
+            .is_some_and(|last| now - *last < cooldown)
+        {
+            debug!("ignoring toggle for {name}, still cooling down");
+            return Ok(());
+        }
+        state.last_toggle.insert(name.into(), now);
+    }
+    let snap = Snapshot::take_for_show().await;
+    // Summoned from another monitor, it comes over rather than closing.
+    let shown_here = app
+        .shown(&snap, name)
+        .is_some_and(|c| !p.follow_to_cursor_monitor || !off_focused_monitor(&snap, c));
+    if shown_here {
+        hide(app, &snap.clients, name).await;
+        nudge_cursor(app, snap.cursor.as_ref());
+    } else {
+        reveal(app, &snap, name).await?;
+        if sticky {
+            app.state.borrow_mut().sticky.insert(name.into());
+        }
+    }
+    Ok(())
+}
+
+async fn reveal(app: &App, snap: &Snapshot, name: &str) -> zbus::Result<()> {
+    show(app, snap, name).await?;
+    // Re-evaluating focus under the cursor would hand it to the new window.
+    if app.plasmoid(name).takes_focus() {
+        nudge_cursor(app, snap.cursor.as_ref());
+    }
+    Ok(())
+}
+
+async fn show_cmd(app: &App, name: &str, sticky: bool) -> zbus::Result<()> {
+    // An explicit show keeps a peeking plasmoid open.
+    app.state.borrow_mut().peeks.remove(name);
+    reveal(app, &Snapshot::take_for_show().await, name).await?;
+    if sticky {
+        app.state.borrow_mut().sticky.insert(name.into());
+    }
+    Ok(())
+}
+
+async fn hide_cmd(app: &App, name: &str) {
+    let snap = Snapshot::take();
+    if app.shown(&snap, name).is_some() {
+        hide(app, &snap.clients, name).await;
+        nudge_cursor(app, snap.cursor.as_ref());
+    }
+}
+
+// Shows a plasmoid and hides it again after `duration_ms`, unless it was
+// hidden, shown explicitly or peeked again in the meantime.
+async fn peek(app: &Rc<App>, name: &str, duration_ms: u64) -> zbus::Result<()> {
+    reveal(app, &Snapshot::take_for_show().await, name).await?;
+    let serial = {
+        let mut state = app.state.borrow_mut();
+        state.next_peek += 1;
+        let serial = state.next_peek;
+        state.peeks.insert(name.into(), serial);
+        serial
+    };
+    let app = app.clone();
+    let name = name.to_string();
+    tokio::task::spawn_local(async move {
+        tokio::time::sleep(Duration::from_millis(duration_ms)).await;
+        if app.state.borrow().peeks.get(&name) == Some(&serial) {
+            hide_cmd(&app, &name).await;
+        }
+    });
+    Ok(())
+}
+
+fn cursor_over(app: &App, name: &str) -> bool {
+    let Some(cursor) = compositor().cursor() else {
+        return false;
+    };
+    app.window(&query_clients(), name).is_some_and(|c| {
+        let (x, y) = (c.at.0 as i64, c.at.1 as i64);
+        (x..x + c.size.0 as i64).contains(&cursor.x) && (y..y + c.size.1 as i64).contains(&cursor.y)
+    })
+}
+
+// (Re)starts the idle timer of a plasmoid with `idle_hide_ms`. The cursor
+// resting on the plasmoid counts as using it, so that's polled meanwhile.
+fn arm_idle_timer(app: &Rc<App>, name: &str) {
+    let idle_ms = app.plasmoid(name).idle_hide_ms;
+    if idle_ms == 0 {
+        return;
+    }
+    let serial = {
+        let mut state = app.state.borrow_mut();
+        state.next_idle += 1;
+        let serial = state.next_idle;
+        state.idle_timers.insert(name.into(), serial);
+        serial
+    };
+    let app = app.clone();
+    let name = name.to_string();
+    tokio::task::spawn_local(async move {
+        let idle = Duration::from_millis(idle_ms);
+        let mut last_used = Instant::now();
+        loop {
+            tokio::time::sleep(Duration::from_millis(IDLE_POLL_MS).min(idle)).await;
+            if app.state.borrow().idle_timers.get(&name) != Some(&serial) {
+                return;
+            }
+            if cursor_over(&app, &name) {
+                last_used = Instant::now();
+            } else if last_used.elapsed() >= idle {
+                break;
+            }
+        }
+        app.state.borrow_mut().idle_timers.remove(&name);
+        if app.auto_hiding() && !app.auto_hide_exempt(&name) {
+            debug!("hiding {name} after {idle_ms}ms idle");
+            hide_cmd(&app, &name).await;
+        }
+    });
+}
+
+async fn cycle(app: &App, names: &[String]) -> zbus::Result<()> {
+    assert!(!names.is_empty(), "missing plasmoid names");
+    if names.len() == 1 {
+        return toggle(app, &names[0], false).await;
+    }
+
+    let snap = Snapshot::take_for_show().await;
+    let visible = names.iter().position(|n| app.shown(&snap, n).is_some());
+    let last = visible.or_else(|| app.state.borrow().cycles.get(names).copied());
+    let next = last.map_or(0, |i| (i + 1) % names.len());
+    if let Some(i) = visible {
+        hide(app, &snap.clients, &names[i]).await;
+    }
+    app.state.borrow_mut().cycles.insert(names.to_vec(), next);
+    show(app, &snap, &names[next]).await?;
+    nudge_cursor(app, snap.cursor.as_ref());
+    Ok(())
+}
+
+async fn config_cmd(app: &App, name: &str) -> zbus::Result<()> {
+    let p = app.plasmoid(name);
+    if p.command.is_some() {
+        return Err(zbus::Error::Failure(format!(
+            "{name} isn't a Plasma applet, it has no settings dialog"
+        )));
+    }
+    let title = match &p.config_title {
+        Some(title) => format!("^({title})$"),
+        None => format!("^(({}) Settings)$", p.title.join("|")),
+    };
+    let rule_name = format!("hypr-plasmoid-{name}-config");
+    set_keyword(rule_prop(&rule_name, "match:title"), title.as_str()).ok();
+    set_keyword(rule_prop(&rule_name, "float"), "yes").ok();
+    set_keyword(rule_prop(&rule_name, "center"), "yes").ok();
+    set_keyword(rule_prop(&rule_name, "enable"), "1").ok();
+
+    dispatch(DispatchType::Exec(&format!(
+        "plasmawindowed --config {}",
+        p.plasmoid
+    )))
+    .ok();
+    // Some plasmawindowed versions ignore --config and exit without a dialog.
+    let re = Regex::new(&title).map_err(|e| zbus::Error::Failure(e.to_string()))?;
+    let Some(address) = wait_for_title(&re, CONFIG_WAIT_MS).await else {
+        return Err(zbus::Error::Failure(format!(
+            "no settings dialog for {name} appeared; this plasmawindowed may not support \
+             --config (configure the applet from a Plasma panel instead), or set config_title \
+             if the dialog has a different title"
+        )));
+    };
+    if p.focus_on_show {
+        dispatch(DispatchType::FocusWindow(WindowIdentifier::Address(
+            address,
+        )))
+        .ok();
+    }
+    Ok(())
+}
+
+// JSON output of the query commands under `--json`. Fields are only ever
+// added, never renamed or removed, so bar widgets can rely on them.
+
+// `list`: `[{"name", "applet", "titles": [...]}]`, in config order.
+#[derive(Serialize)]
+struct ListEntry<'a> {
+    name: &'a str,
+    applet: &'a str,
+    titles: &'a [String],
+}
+
+// `status`: `[{"name", "open", "focused", "address"}]`, in config order.
+// `open` is false for windows parked offscreen; `address` is null only while
+// the plasmoid has no window at all.
+#[derive(Serialize)]
+struct StatusEntry<'a> {
+    name: &'a str,
+    open: bool,
+    focused: bool,
+    address: Option<String>,
+}
+
+// `active`: `{"name"}`, null when no plasmoid has focus.
+#[derive(Serialize)]
+struct Active<'a> {
+    name: Option<&'a str>,
+}
+
+// `which`: `{"name", "applet", "sni": {"destination", "path"} | null}`.
+#[derive(Serialize)]
+struct Which<'a> {
+    name: &'a str,
+    applet: &'a str,
+    sni: Option<SniItem>,
+}
+
+#[derive(Serialize)]
+struct SniItem {
+    destination: String,
+    path: String,
+}
+
+// `sni-list`: `[{"destination", "path", "id"}]`, in the watcher's order. `id`
+// is null for items that didn't answer.
+#[derive(Serialize)]
+struct RegisteredSni {
+    destination: String,
+    path: String,
+    id: Option<String>,
+    title: Option<String>,
+}
+
+fn to_json(value: &impl Serialize) -> String {
+    serde_json::to_string_pretty(value).expect("output is serializable") + "\n"
+}
+
+fn list(app: &App, json: bool) -> String {
+    let entries: Vec<_> = app
+        .cfg
+        .plasmoids
+        .keys()
+        .map(String::as_str)
+        .map(|name| {
+            let p = app.plasmoid(name);
+            ListEntry {
+                name,
+                applet: &p.plasmoid,
+                titles: &p.title,
+            }
+        })
+        .collect();
+    if json {
+        return to_json(&entries);
+    }
+    let width = entries.iter().map(|e| e.name.len()).max().unwrap_or(0);
+    entries
+        .iter()
+        .map(|e| format!("{:width$}  {}\n", e.name, e.applet))
+        .collect()
+}
+
+fn status(app: &App, json: bool) -> String {
+    let snap = Snapshot::take();
+    let focused = compositor().active_window();
+    let entries: Vec<_> = app
+        .cfg
+        .plasmoids
+        .keys()
+        .map(String::as_str)
+        .map(|name| {
+            let address = app.window(&snap.clients, name).map(|c| c.address.clone());
+            let open = app.shown(&snap, name).is_some();
+            StatusEntry {
+                name,
+                open,
+                focused: open && address == focused,
+                address: address.map(|a| a.to_string()),
+            }
+        })
+        .collect();
+    if json {
+        return to_json(&entries);
+    }
+    let width = entries.iter().map(|e| e.name.len()).max().unwrap_or(0);
+    entries
+        .iter()
+        .map(|e| {
+            let state = match (e.open, e.focused) {
+                (true, true) => "focused",
+                (true, false) => "open",
+                _ => "closed",
+            };
+            format!("{:width$}  {state}\n", e.name)
+        })
+        .collect()
+}
+
+fn active(app: &App, json: bool) -> String {
+    let clients = query_clients();
+    let focused = compositor().active_window();
+    let name = focused.and_then(|address| {
+        app.cfg.plasmoids.keys().map(String::as_str).find(|name| {
+            app.window(&clients, name)
+                .is_some_and(|c| c.address == address)
+        })
+    });
+    if json {
+        return to_json(&Active { name });
+    }
+    name.map(|n| format!("{n}\n")).unwrap_or_default()
+}
+
+async fn which(app: &App, name: &str, json: bool) -> String {
+    let p = app.plasmoid(name);
+    let sni = match p.command {
+        Some(_) => None,
+        None => find_sni(app, p).await,
+    };
+    if json {
+        return to_json(&Which {
+            name,
+            applet: &p.plasmoid,
+            sni: sni.map(|(destination, path)| SniItem { destination, path }),
+        });
+    }
+    let mut out = format!("applet: {}\n", p.plasmoid);
+    match (sni, &p.command) {
+        (_, Some(command)) => writeln!(out, "sni: not used, toggle would run {command}"),
+        (Some((dest, path)), None) => writeln!(out, "sni: {dest}{path}"),
+        (None, None) => writeln!(
+            out,
+            "sni: not found, toggle would spawn plasmawindowed --statusnotifier {}",
+            p.plasmoid
+        ),
+    }
+    .ok();
+    out
+}
+
+fn save_geometry(app: &App, name: &str) -> String {
+    let clients = query_clients();
+    let Some(client) = app.window(&clients, name) else {
+        return format!("{name} is not open\n");
+    };
+    let (width, height) = client.size;
+
+    let found = config_files(app.profile.as_deref())
+        .into_iter()
+        .rev()
+        .filter_map(|path| {
+            let text = fs::read_to_string(&path).ok()?;
+            let json: Value = json5::from_str(&text).ok()?;
+            Some((path, text, json))
+        })
+        .find(|(_, _, json)| json.get(name).is_some());
+    let Some((path, text, mut json)) = found else {
+        return format!("{name} isn't in a config file, set its size with --size instead\n");
+    };
+    // Writing the file back as plain JSON would drop its comments.
+    if serde_json::from_str::<Value>(&text).is_err() {
+        return format!(
+            "not saving to {}, it has comments or other JSON5 syntax that would be lost; \
+             set width {width} and height {height} for {name} by hand\n",
+            path.display()
+        );
+    }
+    json[name]["width"] = width.into();
+    json[name]["height"] = height.into();
+    let text = serde_json::to_string_pretty(&json).expect("config is serializable");
+    match fs::write(&path, text + "\n") {
+        Ok(()) => format!("saved {width}x{height} for {name} to {}\n", path.display()),
+        Err(e) => format!("failed to write {}: {e}\n", path.display()),
+    }
+}
+
+// Tracks plasmoid windows that are already open, e.g. when the daemon is
+// restarted, so toggling them hides them instead of opening a second one.
+fn adopt_windows(app: &App) {
+    let clients = query_clients();
+    let active = compositor().active_window();
+    for (name, p) in &app.cfg.plasmoids {
+        if let Some(client) = find_matching_client(&clients, p) {
+            debug!("adopting already open {name} at {}", client.address);
+            app.track(name, &client.address);
+            if active.as_ref() == Some(&client.address) {
+                app.acquire_focus(name);
+            }
+        }
+    }
+}
+
+// One rule per plasmoid, as entries differ in which match props they set.
+fn set_warmup_rules(name: &str, p: &Plasmoid, enable: bool) {
+    let rule_name = &format!("hypr-plasmoid-warmup-{name}");
+    if !enable {
+        set_keyword(rule_prop(rule_name, "enable"), "0").ok();
+        return;
+    }
+    set_match_rules(rule_name, p);
+    set_keyword(
+        rule_prop(rule_name, "workspace"),
+        "special:hypr-plasmoid-warmup silent",
+    )
+    .ok();
+    set_keyword(rule_prop(rule_name, "opacity"), "0").ok();
+    set_keyword(rule_prop(rule_name, "no_anim"), "yes").ok();
+    set_keyword(rule_prop(rule_name, "no_initial_focus"), "yes").ok();
+    set_keyword(rule_prop(rule_name, "enable"), "1").ok();
+}
+
+async fn warm_up(app: &App) {
+    for (name, p) in &app.cfg.plasmoids {
+        // Warming up an adopted plasmoid would close the user's window, and
+        // there's no SNI item to register for plain commands.
+        if p.command.is_some() || app.state.borrow().windows.contains_key(name) {
+            continue;
+        }
+        let stealth = app.cfg.settings.warmup.stealth;
+        if stealth {
+            set_warmup_rules(name, p, true);
+        }
+        let spawned = spawn_plasmoid(p);
+        if let Some(address) = wait_for_window(p, spawned, 2000).await {
+            dispatch(DispatchType::CloseWindow(WindowIdentifier::Address(
+                address,
+            )))
+            .ok();
+        }
+        if stealth {
+            set_warmup_rules(name, p, false);
+        }
+    }
+    set_keyword(rule_prop("plasmoid-warmup", "enable"), "0").ok();
+}
+
+fn is_ignored_workspace(settings: &Settings, workspace: &WorkspaceType) -> bool {
+    match workspace {
+        WorkspaceType::Special(_) if settings.ignore_special_workspaces => true,
+        _ => settings
+            .ignore_workspaces
+            .iter()
+            .any(|name| *name == workspace.to_string()),
+    }
+}
+
+// Hyprland runs exec binds through sh.
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', r"'\''"))
+}
+
+// Binds go to the daemon they were registered by, with its profile and bus.
+fn bind_command(
+    exe: &Path,
+    profile: Option<&str>,
+    bus_address: Option<&str>,
+    name: &str,
+) -> String {
+    let mut command = shell_quote(&exe.to_string_lossy());
+    if let Some(profile) = profile {
+        write!(command, " --profile {}", shell_quote(profile)).ok();
+    }
+    if let Some(address) = bus_address {
+        write!(command, " --bus-address {}", shell_quote(address)).ok();
+    }
+    write!(command, " toggle {}", shell_quote(name)).ok();
+    command
+}
+
+fn register_binds(cfg: &Config, profile: Option<&str>, bus_address: Option<&str>) {
+    let exe = env::current_exe().expect("cannot resolve own executable");
+    for (name, p) in &cfg.plasmoids {
+        if let Some(bind) = &p.bind {
+            let command = bind_command(&exe, profile, bus_address, name);
+            let action = format!("{bind}, exec, {command}");
+            if let Err(e) = set_keyword("bind", action) {
+                eprintln!("failed to bind {bind} for {name}: {e}");
+            }
+        }
+    }
+}
+
+fn unregister_binds(cfg: &Config) {
+    for p in cfg.plasmoids.values() {
+        if let Some(bind) = &p.bind {
+            set_keyword("unbind", bind.as_str()).ok();
+        }
+    }
+}
+
+// Undoes what a daemon that died without cleaning up may have left set. The
+// keywords go back to Hyprland's defaults, not to the user's config, which
+// only `hyprctl reload` restores.
+fn reset(cfg: &Config) -> String {
+    disable_window_rules(cfg);
+    for name in cfg.plasmoids.keys() {
+        for rule_name in [
+            format!("hypr-plasmoid-{name}-config"),
+            format!("hypr-plasmoid-warmup-{name}"),
+        ] {
+            set_keyword(rule_prop(&rule_name, "enable"), "0").ok();
+        }
+    }
+    set_keyword(rule_prop("hypr-plasmoid-focus", "enable"), "0").ok();
+    unregister_binds(cfg);
+    let settings = &cfg.settings;
+    if settings.manage_follow_mouse {
+        set_keyword("input:follow_mouse", "1").ok();
+    }
+    if settings.manage_float_switch {
+        set_keyword("input:float_switch_override_focus", "1").ok();
+    }
+    if settings.manage_no_warps {
+        set_keyword("cursor:no_warps", "0").ok();
+    }
+    format!(
+        "disabled the rules and binds of {} plasmoids\n",
+        cfg.plasmoids.len()
+    )
+}
+
+fn runtime_file(name: &str) -> PathBuf {
+    env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(env::temp_dir)
+        .join(name)
+}
+
+// Unlike the runtime files this survives logging out, so a session can be
+// restored on the next login too.
+fn session_file(profile: Option<&str>) -> PathBuf {
+    env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(format!("{}/.local/state", env::var("HOME").unwrap())))
+        .join("hypr-plasmoid")
+        .join(profile_file_name("session", profile, "json"))
+}
+
+#[derive(Serialize, Deserialize)]
+struct SessionEntry {
+    name: String,
+    x: i64,
+    y: i64,
+}
+
+fn save_session(app: &App) {
+    let clients = query_clients();
+    let entries: Vec<_> = app
+        .state
+        .borrow()
+        .shown
+        .iter()
+        .filter_map(|name| {
+            let client = app.window(&clients, name)?;
+            Some(SessionEntry {
+                name: name.clone(),
+                x: client.at.0 as i64,
+                y: client.at.1 as i64,
+            })
+        })
+        .collect();
+    let path = session_file(app.profile.as_deref());
+    let saved = path
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| fs::write(&path, to_json(&entries)));
+    if let Err(e) = saved {
+        eprintln!("failed to save session to {}: {e}", path.display());
+    }
+}
+
+async fn restore_session(app: &App) {
+    let path = session_file(app.profile.as_deref());
+    let Ok(text) = fs::read_to_string(&path) else {
+        return;
+    };
+    fs::remove_file(&path).ok();
+    let entries: Vec<SessionEntry> = match serde_json::from_str(&text) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("ignoring invalid session file {}: {e}", path.display());
+            return;
+        }
+    };
+    for entry in entries {
+        // Plasmoids that were removed from the config or are already open
+        // (and adopted) are left alone.
+        if !app.cfg.plasmoids.contains_key(&entry.name)
+            || app.state.borrow().windows.contains_key(&entry.name)
+        {
+            continue;
+        }
+        debug!("restoring {} at {} {}", entry.name, entry.x, entry.y);
+        if let Err(e) = show(app, &Snapshot::take_for_show().await, &entry.name).await {
+            eprintln!("failed to restore {}: {e}", entry.name);
+            continue;
+        }
+        if let Some(client) = app.window(&query_clients(), &entry.name) {
+            move_window(&client.address, entry.x, entry.y);
+        }
+    }
+}
+
+// Each profile runs its own daemon, so its files are kept apart.
+fn profile_file_name(base: &str, profile: Option<&str>, ext: &str) -> String {
+    match profile {
+        Some(profile) => format!("{base}.{profile}.{ext}"),
+        None => format!("{base}.{ext}"),
+    }
+}
+
+fn ready_file(profile: Option<&str>) -> PathBuf {
+    runtime_file(&profile_file_name("hypr-plasmoid", profile, "ready"))
+}
+
+fn socket_path(profile: Option<&str>) -> PathBuf {
+    runtime_file(&profile_file_name("hypr-plasmoid", profile, "sock"))
+}
+
+async fn forward(profile: Option<&str>, args: &[String]) -> Option<Result<String, String>> {
+    let mut stream = UnixStream::connect(socket_path(profile)).await.ok()?;
+    let mut request = serde_json::to_vec(args).ok()?;
+    request.push(b'\n');
+    stream.write_all(&request).await.ok()?;
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply).await.ok()?;
+    Some(
+        serde_json::from_slice(&reply).unwrap_or_else(|_| Err("daemon dropped the request".into())),
+    )
+}
+
+async fn handle_request(app: Rc<App>, stream: UnixStream) {
+    let (read, mut write) = stream.into_split();
+    let mut line = String::new();
+    if BufReader::new(read).read_line(&mut line).await.is_err() {
+        return;
+    }
+    let reply = match serde_json::from_str::<Vec<String>>(&line) {
+        Ok(args) => match parse_cli(&args) {
+            Ok(cli) if cli.command.forwarded() => run(&app, &cli).await.map_err(|e| e.to_string()),
+            Ok(_) => Err("command can't be run by the daemon".into()),
+            Err(e) => Err(e.to_string()),
+        },
+        Err(e) => Err(format!("malformed request: {e}")),
+    };
+    let reply = serde_json::to_vec(&reply).expect("reply is serializable");
+    write.write_all(&reply).await.ok();
+}
+
+async fn serve_metrics(app: Rc<App>, port: u16) {
+    // The daemon is still useful without metrics, and returning would stop it.
+    let listener = match TcpListener::bind(("127.0.0.1", port)).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("not serving metrics, can't bind port {port}: {e}");
+            return std::future::pending().await;
+        }
+    };
+    loop {
+        let Ok((mut stream, _)) = listener.accept().await else {
+            continue;
+        };
+        let app = app.clone();
+        tokio::task::spawn_local(async move {
+            // Any request gets the metrics; there's only the one endpoint.
+            let mut request = [0; 1024];
+            if stream.read(&mut request).await.is_err() {
+                return;
+            }
+            let body = app.state.borrow().metrics.prometheus();
+            let reply = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            stream.write_all(reply.as_bytes()).await.ok();
+        });
+    }
+}
+
+// Requests run in `requests` so shutdown can let them finish first.
+async fn serve(app: Rc<App>, requests: Rc<RefCell<JoinSet<()>>>) {
+    let path = socket_path(app.profile.as_deref());
+    fs::remove_file(&path).ok();
+    let listener = UnixListener::bind(&path).expect("failed to bind control socket");
+    loop {
+        if let Ok((stream, _)) = listener.accept().await {
+            let mut requests = requests.borrow_mut();
+            while requests.try_join_next().is_some() {}
+            requests.spawn_local(handle_request(app.clone(), stream));
+        }
+    }
+}
+
+// A show stopped halfway can leave a window spawned but never tracked, or
+// rules set for a window that never came, so give it a moment to finish.
+async fn finish_requests(requests: &RefCell<JoinSet<()>>) {
+    let mut requests = requests.take();
+    if requests.is_empty() {
+        return;
+    }
+    debug!(
+        "waiting for {} requests before shutting down",
+        requests.len()
+    );
+    let finished = tokio::time::timeout(Duration::from_millis(SHUTDOWN_WAIT_MS), async {
+        while requests.join_next().await.is_some() {}
+    })
+    .await;
+    if finished.is_err() {
+        eprintln!(
+            "gave up on {} requests still running after {SHUTDOWN_WAIT_MS}ms",
+            requests.len()
+        );
+    }
+}
+
+fn notify_ready(profile: Option<&str>) {
+    if let Some(socket) = env::var_os("NOTIFY_SOCKET") {
+        let addr = match socket.as_bytes().strip_prefix(b"@") {
+            Some(name) => SocketAddr::from_abstract_name(name),
+            None => SocketAddr::from_pathname(&socket),
+        };
+        let sent = UnixDatagram::unbound().and_then(|sock| sock.send_to_addr(b"READY=1", &addr?));
+        if let Err(e) = sent {
+            eprintln!("failed to notify systemd: {e}");
+        }
+    }
+    fs::write(ready_file(profile), std::process::id().to_string()).ok();
+}
+
+async fn shutdown_signal() {
+    let mut term = signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+    tokio::select! {
+        _ = term.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+}
+
+// Windows that don't count as focus leaving the plasmoids, like helper popups.
+struct Friendly {
+    titles: Vec<Regex>,
+    classes: Vec<Regex>,
+}
+
+impl Friendly {
+    fn matches(&self, title: &str, class: &str) -> bool {
+        self.titles.iter().any(|re| re.is_match(title))
+            || self.classes.iter().any(|re| re.is_match(class))
+    }
+}
+
+// Where focus went, as far as the active window handler cares.
+enum FocusTarget {
+    // A friendly window, which counts as staying with the plasmoid.
+    Friendly,
+    // A window with a plasmoid's title, and the plasmoid if we track it.
+    Plasmoid(Option<String>),
+    Other,
+}
+
+#[derive(Debug, PartialEq)]
+enum FocusAction {
+    Stay,
+    // Focus is on a plasmoid. `hide` is an exclusive plasmoid focus moved
+    // straight over from, which showing this one non-exclusively (or
+    // adopting it) left behind.
+    Enter {
+        name: Option<String>,
+        hide: Option<String>,
+    },
+    // Focus left the plasmoids for some other window, or none at all.
+    Leave,
+}
+
+// The daemon's reaction to a focus change, given the plasmoid that had focus
+// before, which it updates. `exclusive` tells whether leaving a plasmoid for
+// another one hides it.
+fn on_active_window(
+    last_active: &mut Option<String>,
+    target: FocusTarget,
+    exclusive: impl Fn(&str) -> bool,
+) -> FocusAction {
+    match target {
+        FocusTarget::Friendly => FocusAction::Stay,
+        FocusTarget::Other => {
+            *last_active = None;
+            FocusAction::Leave
+        }
+        FocusTarget::Plasmoid(name) => {
+            let previous = std::mem::replace(last_active, name.clone());
+            let hide = previous.filter(|previous| {
+                name.as_ref().is_some_and(|name| name != previous) && exclusive(previous)
+            });
+            FocusAction::Enter { name, hide }
+        }
+    }
+}
+
+fn compile_patterns(patterns: &[String]) -> Vec<Regex> {
+    patterns
+        .iter()
+        .filter_map(|pattern| match Regex::new(&format!("^({pattern})$")) {
+            Ok(re) => Some(re),
+            Err(e) => {
+                eprintln!("ignoring invalid pattern {pattern}: {e}");
+                None
+            }
+        })
+        .collect()
+}
+
+async fn daemon(app: Rc<App>, initial_toggle: Option<&str>, bus_address: Option<&str>) {
+    let cfg = &app.cfg;
+    let startup = &cfg.settings.startup;
+    if startup.delay_ms > 0 {
+        tokio::time::sleep(Duration::from_millis(startup.delay_ms)).await;
+    }
+    // Applets spawned before the watcher is up never register their SNI item.
+    if !wait_for_watcher(&app, startup.watcher_timeout_ms).await {
+        eprintln!("warming up without {WATCHER_SERVICE}");
+    }
+    adopt_windows(&app);
+    warm_up(&app).await;
+    if cfg.settings.restore_session {
+        restore_session(&app).await;
+    }
+    if let Some(name) = initial_toggle {
+        if !cfg.plasmoids.contains_key(name) {
+            eprintln!("not toggling unknown plasmoid {name}");
+        } else if let Err(e) = toggle(&app, name, false).await {
+            eprintln!("failed to toggle {name}: {e}");
+        }
+    }
+    register_binds(cfg, app.profile.as_deref(), bus_address);
+    notify_ready(app.profile.as_deref());
+
+    let patterns: Vec<_> = cfg
+        .plasmoids
+        .values()
+        .filter_map(|p| Regex::new(&title_rule(p)).ok())
+        .collect();
+    let friendly = Rc::new(Friendly {
+        titles: compile_patterns(&cfg.settings.friendly_titles),
+        classes: compile_patterns(&cfg.settings.friendly_classes),
+    });
+    let mut listener = EventListener::new();
+
+    let app2 = app.clone();
+    let ready_at = Instant::now();
+    let grace = Duration::from_millis(startup.workspace_grace_ms);
+    listener.add_workspace_changed_handler(move |data| {
+        if ready_at.elapsed() < grace {
+            debug!("ignoring switch to workspace {} during startup", data.name);
+            return;
+        }
+        if app2.auto_hiding() && !is_ignored_workspace(&app2.cfg.settings, &data.name) {
+            let app = app2.clone();
+            tokio::task::spawn_local(async move { auto_hide_all(&app, true).await });
+        }
+    });
+
+    // Non-focusable plasmoids never lose focus, so the focus handler below
+    // can miss them; any other window opening dismisses them too.
+    let app6 = app.clone();
+    let opened_patterns = patterns.clone();
+    let opened_friendly = friendly.clone();
+    listener.add_window_opened_handler(move |data| {
+        let dominated = opened_patterns
+            .iter()
+            .any(|re| re.is_match(&data.window_title))
+            || opened_friendly.matches(&data.window_title, &data.window_class);
+        if !dominated && app6.auto_hiding() {
+            let app = app6.clone();
+            tokio::task::spawn_local(async move { hide_unfocusable(&app).await });
+        }
+    });
+
+    let app3 = app.clone();
+    listener.add_active_window_changed_handler(move |data| {
+        let target = match &data {
+            Some(d) if friendly.matches(&d.title, &d.class) => FocusTarget::Friendly,
+            Some(d) if patterns.iter().any(|re| re.is_match(&d.title)) => {
+                FocusTarget::Plasmoid(app3.tracked_name(&d.address))
+            }
+            _ => FocusTarget::Other,
+        };
+        // Taken out of the state, which `auto_hide_exempt` needs to borrow meanwhile.
+        let mut last_active = app3.state.borrow_mut().last_active.take();
+        let action = on_active_window(&mut last_active, target, |name| {
+            app3.plasmoid(name).exclusive && !app3.auto_hide_exempt(name)
+        });
+        app3.state.borrow_mut().last_active = last_active;
+        match action {
+            FocusAction::Stay => {}
+            FocusAction::Enter { name, hide: left } => {
+                match &name {
+                    Some(name) => {
+                        arm_idle_timer(&app3, name);
+                        app3.acquire_focus(name);
+                    }
+                    None => set_focus_mode(&app3.cfg, true),
+                }
+                if let Some(left) = left.filter(|_| app3.auto_hiding()) {
+                    let app = app3.clone();
+                    tokio::task::spawn_local(async move {
+                        hide(&app, &query_clients(), &left).await;
+                    });
+                }
+            }
+            FocusAction::Leave => {
+                app3.release_sticky_focus();
+                // Plasmoids still open keep focus mode on until they're hidden.
+                if app3.state.borrow().focus_holders.is_empty() {
+                    set_focus_mode(&app3.cfg, false);
+                }
+                if app3.auto_hiding() {
+                    let app = app3.clone();
+                    tokio::task::spawn_local(async move { auto_hide_all(&app, false).await });
+                }
+            }
+        }
+    });
+
+    let app4 = app.clone();
+    listener.add_monitor_removed_handler(move |_| {
+        let app = app4.clone();
+        tokio::task::spawn_local(async move { rescue_stranded(&app).await });
+    });
+
+    let app7 = app.clone();
+    listener.add_monitor_added_handler(move |_| place_unplaced(&app7));
+
+    let app5 = app.clone();
+    listener.add_window_closed_handler(move |address| app5.window_closed(&address));
+
+    let metrics = &cfg.settings.metrics;
+    let metrics_port = metrics.port.filter(|_| metrics.enabled);
+    let requests = Rc::new(RefCell::new(JoinSet::new()));
+    tokio::select! {
+        res = async {
+            if headless() {
+                std::future::pending().await
+            } else {
+                listener.start_listener_async().await
+            }
+        } => res.expect("Failed to start listener"),
+        _ = serve(app.clone(), requests.clone()) => {}
+        _ = async {
+            match metrics_port {
+                Some(port) => serve_metrics(app.clone(), port).await,
+                None => std::future::pending().await,
+            }
+        } => {}
+        _ = shutdown_signal() => {}
+    }
+    finish_requests(&requests).await;
+    if cfg.settings.restore_session {
+        save_session(&app);
+    }
+    unregister_binds(cfg);
+    disable_window_rules(cfg);
+    fs::remove_file(socket_path(app.profile.as_deref())).ok();
+    fs::remove_file(ready_file(app.profile.as_deref())).ok();
+}
+
+fn report(ok: bool, check: &str, detail: &str, hint: &str) -> bool {
+    if ok {
+        println!("[ok]   {check}: {detail}");
+    } else {
+        println!("[FAIL] {check}: {detail}");
+        println!("       hint: {hint}");
+    }
+    ok
+}
+
+async fn doctor(profile: Option<&str>, bus_address: Option<&str>) -> bool {
+    let mut healthy = true;
+
+    let signature = env::var("HYPRLAND_INSTANCE_SIGNATURE");
+    healthy &= report(
+        signature.is_ok(),
+        "Hyprland",
+        signature
+            .as_deref()
+            .unwrap_or("HYPRLAND_INSTANCE_SIGNATURE is not set"),
+        "run hypr-plasmoid from inside a Hyprland session",
+    );
+
+    let conn = connect(bus_address).await;
+    healthy &= report(
+        conn.is_ok(),
+        "session D-Bus",
+        &conn
+            .as_ref()
+            .map_or_else(|e| e.to_string(), |_| "connected".into()),
+        "make sure DBUS_SESSION_BUS_ADDRESS points at a running session bus",
+    );
+    if let Ok(conn) = &conn {
+        let watcher = has_owner(conn, WATCHER_SERVICE).await;
+        // Without a watcher we can still spawn plasmoids, just never reuse them.
+        report(
+            watcher,
+            "StatusNotifierWatcher",
+            if watcher {
+                "available"
+            } else {
+                "not on the bus"
+            },
+            "start plasmashell or another tray host that provides org.kde.StatusNotifierWatcher",
+        );
+    }
+
+    let plasmawindowed = env::var_os("PATH")
+        .map(|paths| env::split_paths(&paths).find(|dir| dir.join("plasmawindowed").is_file()))
+        .unwrap_or_default();
+    healthy &= report(
+        plasmawindowed.is_some(),
+        "plasmawindowed",
+        &plasmawindowed.map_or("not found in PATH".into(), |dir| {
+            dir.join("plasmawindowed").display().to_string()
+        }),
+        "install plasma-workspace (it ships plasmawindowed)",
+    );
+
+    let cfg = try_load_config(profile);
+    healthy &= report(
+        cfg.as_ref().is_ok_and(|cfg| !cfg.plasmoids.is_empty()),
+        "config",
+        &cfg.as_ref().map_or_else(Clone::clone, |cfg| {
+            format!("{} plasmoids configured", cfg.plasmoids.len())
+        }),
+        "create ~/.config/hypr/plasmoids.json or files in ~/.config/hypr/plasmoids.d/",
+    );
+
+    healthy
+}
+
+#[derive(Serialize)]
+struct Applet {
+    id: String,
+    name: String,
+}
+
+fn data_dirs() -> Vec<PathBuf> {
+    let home = env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(format!("{}/.local/share", env::var("HOME").unwrap())));
+    let system =
+        env::var_os("XDG_DATA_DIRS").unwrap_or_else(|| "/usr/local/share:/usr/share".into());
+    std::iter::once(home)
+        .chain(env::split_paths(&system))
+        .collect()
+}
+
+fn installed_applets() -> Vec<Applet> {
+    let mut applets: Vec<Applet> = Vec::new();
+    for dir in data_dirs() {
+        let Ok(entries) = fs::read_dir(dir.join("plasma/plasmoids")) else {
+            continue;
+        };
+        for entry in entries.filter_map(Result::ok) {
+            let metadata = fs::read_to_string(entry.path().join("metadata.json"))
+                .ok()
+                .and_then(|text| serde_json::from_str::<Value>(&text).ok());
+            let Some(metadata) = metadata else {
+                continue;
+            };
+            let plugin = &metadata["KPlugin"];
+            let id = plugin["Id"]
+                .as_str()
+                .map(String::from)
+                .unwrap_or_else(|| entry.file_name().to_string_lossy().into_owned());
+            // Earlier data dirs shadow later ones, like Plasma's own lookup.
+            if applets.iter().any(|a| a.id == id) {
+                continue;
+            }
+            let name = plugin["Name"].as_str().unwrap_or_default().to_string();
+            applets.push(Applet { id, name });
+        }
+    }
+    applets.sort_by(|a, b| a.id.cmp(&b.id));
+    applets
+}
+
+fn applets(json: bool) -> String {
+    let applets = installed_applets();
+    if json {
+        return to_json(&applets);
+    }
+    let width = applets.iter().map(|a| a.id.len()).max().unwrap_or(0);
+    applets
+        .iter()
+        .map(|a| format!("{:width$}  {}\n", a.id, a.name))
+        .collect()
+}
+
+// Every item registered with the watcher, for finding out what `find_sni` is
+// up against when it doesn't find an applet.
+async fn sni_list(json: bool, bus_address: Option<&str>) -> Result<String, String> {
+    let conn = connect(bus_address).await.map_err(|e| e.to_string())?;
+    let items = registered_items(&conn)
+        .await
+        .ok_or_else(|| format!("{WATCHER_SERVICE} is unavailable"))?;
+    let mut entries = Vec::new();
+    for (destination, path) in items {
+        let id = sni_id(&conn, &destination, &path).await;
+        let title = sni_title(&conn, &destination, &path).await;
+        entries.push(RegisteredSni {
+            destination,
+            path,
+            id,
+            title,
+        });
+    }
+    if json {
+        return Ok(to_json(&entries));
+    }
+    let width = entries
+        .iter()
+        .map(|e| e.destination.len() + e.path.len())
+        .max()
+        .unwrap_or(0);
+    Ok(entries
+        .iter()
+        .map(|e| {
+            let item = format!("{}{}", e.destination, e.path);
+            format!(
+                "{item:width$}  {}  {}\n",
+                e.id.as_deref().unwrap_or("-"),
+                e.title.as_deref().unwrap_or("-")
+            )
+        })
+        .collect())
+}
+
+fn version() -> String {
+    let hyprland = compositor()
+        .version()
+        .unwrap_or_else(|e| format!("unavailable ({e})"));
+    format!(
+        "hypr-plasmoid {} ({})\nHyprland {hyprland}\n",
+        env!("CARGO_PKG_VERSION"),
+        env!("HYPR_PLASMOID_COMMIT"),
+    )
+}
+
+fn demo_mode(app: &App, switch: Option<Switch>) -> String {
+    let mut state = app.state.borrow_mut();
+    match switch {
+        Some(Switch::On) => state.demo_mode = true,
+        Some(Switch::Off) => state.demo_mode = false,
+        None => {}
+    }
+    format!("demo-mode {}\n", if state.demo_mode { "on" } else { "off" })
+}
+
+fn metrics(app: &App, json: bool) -> String {
+    if !app.cfg.settings.metrics.enabled {
+        return "metrics are disabled, set settings.metrics.enabled\n".into();
+    }
+    let state = app.state.borrow();
+    if json {
+        to_json(&state.metrics)
+    } else {
+        state.metrics.prometheus()
+    }
+}
+
+fn auto_hide(app: &App, switch: Option<Switch>) -> String {
+    let mut state = app.state.borrow_mut();
+    match switch {
+        Some(Switch::On) => state.auto_hide_paused = false,
+        Some(Switch::Off) => state.auto_hide_paused = true,
+        None => {}
+    }
+    format!(
+        "auto-hide {}\n",
+        if state.auto_hide_paused { "off" } else { "on" }
+    )
+}
+
+#[derive(Parser)]
+#[command(
+    name = "hypr-plasmoid",
+    version,
+    long_version = concat!(env!("CARGO_PKG_VERSION"), " (", env!("HYPR_PLASMOID_COMMIT"), ")"),
+    about = "Show KDE Plasma widgets as popups in Hyprland",
+    after_help = "Commands other than applets, sni-list, doctor, reset and version go through \
+                  the daemon when it's running."
+)]
+struct Cli {
+    /// Print list, status, active, which, metrics, applets and sni-list as JSON
+    #[arg(long, global = true)]
+    json: bool,
+    /// Use ~/.config/hypr/plasmoids.<PROFILE>.json instead of plasmoids.json
+    ///
+    /// Files in plasmoids.d still apply on top of it. Every profile runs its
+    /// own daemon, which commands with the same --profile go through.
+    #[arg(long, global = true, value_parser = parse_profile)]
+    profile: Option<String>,
+    /// Talk to the D-Bus bus at this address instead of the session bus
+    ///
+    /// For Plasma running in a container or sandbox with its own bus.
+    /// Commands that go through the daemon use the bus it was started with.
+    #[arg(long, global = true, value_name = "ADDRESS")]
+    bus_address: Option<String>,
+    #[command(subcommand)]
+    command: Cmd,
+}
+
+#[derive(Subcommand)]
+enum Cmd {
+    /// Show or hide a plasmoid
+    Toggle(ShowArgs),
+    /// Show a plasmoid if it's hidden
+    Show(ShowArgs),
+    /// Hide a plasmoid if it's shown
+    Hide(Target),
+    /// Show a plasmoid, then hide it again after a while
+    ///
+    /// The plasmoid stays open if it's shown explicitly in the meantime.
+    Peek {
+        #[command(flatten)]
+        target: Target,
+        /// How long to show the plasmoid for, in milliseconds
+        duration_ms: u64,
+    },
+    /// Show the next plasmoid in the list, hiding the current one
+    Cycle {
+        /// Plasmoids to cycle through, by their names in the config
+        #[arg(required = true)]
+        names: Vec<String>,
+    },
+    /// Toggle the plasmoid at a position in the config, counting from 1
+    ///
+    /// Plasmoids are numbered in the order they're defined, with files in
+    /// plasmoids.d following plasmoids.json.
+    ToggleIndex { index: usize },
+    /// Open a plasmoid's settings dialog
+    Config(Target),
+    /// Print the applet id and SNI item for a plasmoid
+    Which(Target),
+    /// Write a plasmoid's current size back to its config file
+    SaveGeometry(Target),
+    /// Hide every plasmoid
+    HideAll,
+    /// Hide the plasmoids on the active workspace
+    HideHere,
+    /// List configured plasmoids
+    List,
+    /// Print the config in effect, with defaults, env vars and files resolved
+    ConfigDump,
+    /// Print whether each plasmoid is open or focused
+    Status,
+    /// Print the plasmoid that has focus, if any
+    Active,
+    /// Pause or resume the daemon's auto-hiding, or print whether it's on
+    AutoHide { state: Option<Switch> },
+    /// Turn off auto-hiding and cursor nudging at once, e.g. for recordings
+    DemoMode { state: Option<Switch> },
+    /// Print the daemon's counters in Prometheus format
+    ///
+    /// Needs settings.metrics.enabled in the config.
+    Metrics,
+    /// List installed Plasma applets usable as `plasmoid`
+    Applets,
+    /// List every registered StatusNotifierItem with its id and title
+    SniList,
+    /// Check the environment for common problems
+    Doctor,
+    /// Undo window rules, binds and focus settings left behind by a daemon
+    ///
+    /// For recovering after a crash; a running daemon sets them up again as
+    /// it needs them.
+    Reset,
+    /// Print version information, including Hyprland's
+    Version,
+    /// Warm up plasmoids and auto-hide them on focus loss
+    Daemon {
+        /// Toggle this plasmoid once warm-up is done, e.g. to open it on login
+        #[arg(long, value_name = "NAME")]
+        toggle: Option<String>,
+    },
+    /// Print a man page
+    #[command(hide = true)]
+    Man,
+}
+
+#[derive(Args)]
+struct Target {
+    /// The plasmoid's name in the config
+    #[arg(required_unless_present = "applet")]
+    name: Option<String>,
+    /// Use a one-off plasmoid for this applet instead, without editing the config
+    #[arg(long, value_name = "ID", conflicts_with = "name")]
+    applet: Option<String>,
+    /// Size of the one-off plasmoid
+    #[arg(
+        long,
+        value_name = "WxH",
+        requires = "applet",
+        conflicts_with = "name",
+        value_parser = parse_size
+    )]
+    size: Option<(u32, u32)>,
+    /// Title of the one-off plasmoid's window, by default the applet's name
+    #[arg(long, requires = "applet", conflicts_with = "name")]
+    title: Option<String>,
+}
+
+impl Target {
+    // A one-off plasmoid is named after its applet.
+    fn name(&self) -> &str {
+        self.name
+            .as_deref()
+            .or(self.applet.as_deref())
+            .expect("clap requires a name or --applet")
+    }
+}
+
+#[derive(Args)]
+struct ShowArgs {
+    #[command(flatten)]
+    target: Target,
+    /// Keep the plasmoid open when focus moves elsewhere, until it's hidden
+    #[arg(long)]
+    sticky: bool,
+}
+
+#[derive(ValueEnum, Clone, Copy)]
+enum Switch {
+    On,
+    Off,
+}
+
+impl Cmd {
+    // Commands handled by `run`, which go through the daemon when it's running.
+    fn forwarded(&self) -> bool {
+        !matches!(
+            self,
+            Cmd::Applets
+                | Cmd::SniList
+                | Cmd::Doctor
+                | Cmd::Reset
+                | Cmd::Version
+                | Cmd::Daemon { .. }
+                | Cmd::Man
+        )
+    }
+
+    fn target(&self) -> Option<&Target> {
+        match self {
+            Cmd::Toggle(ShowArgs { target, .. })
+            | Cmd::Show(ShowArgs { target, .. })
+            | Cmd::Peek { target, .. }
+            | Cmd::Hide(target)
+            | Cmd::Config(target)
+            | Cmd::Which(target)
+            | Cmd::SaveGeometry(target) => Some(target),
+            _ => None,
+        }
+    }
+
+    fn daemon_only(&self) -> bool {
+        matches!(
+            self,
+            Cmd::AutoHide { .. } | Cmd::DemoMode { .. } | Cmd::Metrics
+        )
+    }
+}
+
+fn parse_profile(name: &str) -> Result<String, String> {
+    if name.is_empty() || name.contains(['/', '.']) {
+        return Err("expected a name without dots or slashes".into());
+    }
+    Ok(name.into())
+}
+
+fn parse_size(size: &str) -> Result<(u32, u32), String> {
+    size.split_once('x')
+        .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)))
+        .ok_or_else(|| "expected WIDTHxHEIGHT".into())
+}
+
+fn parse_cli(args: &[String]) -> Result<Cli, clap::Error> {
+    Cli::try_parse_from(std::iter::once("hypr-plasmoid").chain(args.iter().map(String::as_str)))
+}
+
+async fn run(app: &Rc<App>, cli: &Cli) -> zbus::Result<String> {
+    let names = match &cli.command {
+        Cmd::Cycle { names } => names.iter().map(String::as_str).collect(),
+        cmd => cmd
+            .target()
+            .map(Target::name)
+            .into_iter()
+            .collect::<Vec<_>>(),
+    };
+    if let Some(name) = names.iter().find(|n| !app.cfg.plasmoids.contains_key(**n)) {
+        return Err(zbus::Error::Failure(format!("unknown plasmoid {name}")));
+    }
+    let json = cli.json;
+    let mut out = String::new();
+    match &cli.command {
+        Cmd::Toggle(ShowArgs { target, sticky }) => toggle(app, target.name(), *sticky).await?,
+        Cmd::Show(ShowArgs { target, sticky }) => show_cmd(app, target.name(), *sticky).await?,
+        Cmd::Hide(target) => hide_cmd(app, target.name()).await,
+        Cmd::Peek {
+            target,
+            duration_ms,
+        } => peek(app, target.name(), *duration_ms).await?,
+        Cmd::Cycle { names } => cycle(app, names).await?,
+        Cmd::ToggleIndex { index } => {
+            let count = app.cfg.plasmoids.len();
+            let (name, _) = index
+                .checked_sub(1)
+                .and_then(|i| app.cfg.plasmoids.get_index(i))
+                .ok_or_else(|| {
+                    zbus::Error::Failure(format!(
+                        "no plasmoid at index {index}, expected 1 to {count}"
+                    ))
+                })?;
+            toggle(app, name, false).await?
+        }
+        Cmd::Config(target) => config_cmd(app, target.name()).await?,
+        Cmd::Which(target) => out = which(app, target.name(), json).await,
+        Cmd::SaveGeometry(target) => out = save_geometry(app, target.name()),
+        Cmd::HideAll => hide_all(app, &query_clients(), None).await,
+        Cmd::HideHere => hide_here(app).await,
+        Cmd::List => out = list(app, json),
+        Cmd::ConfigDump => out = to_json(&app.cfg),
+        Cmd::Status => out = status(app, json),
+        Cmd::Active => out = active(app, json),
+        Cmd::AutoHide { state } => out = auto_hide(app, *state),
+        Cmd::DemoMode { state } => out = demo_mode(app, *state),
+        Cmd::Metrics => out = metrics(app, json),
+        Cmd::Applets
+        | Cmd::SniList
+        | Cmd::Doctor
+        | Cmd::Reset
+        | Cmd::Version
+        | Cmd::Daemon { .. }
+        | Cmd::Man => {
+            unreachable!("not forwarded")
+        }
+    }
+    Ok(out)
+}
+
+// `--applet <id> [--size WxH] [--title T]` defines a one-off plasmoid named
+// after the applet, for trying an applet out without editing the config.
+fn inline_plasmoid(target: &Target) -> Result<Option<(String, Plasmoid)>, String> {
+    let Some(applet) = &target.applet else {
+        return Ok(None);
+    };
+    let title = target.title.clone().unwrap_or_else(|| {
+        let name = installed_applets()
+            .into_iter()
+            .find(|a| a.id == *applet)
+            .map(|a| a.name)
+            .filter(|name| !name.is_empty());
+        regex::escape(name.as_deref().unwrap_or(applet))
+    });
+    let mut entry = serde_json::json!({ "title": title, "plasmoid": applet });
+    match target.size {
+        Some((width, height)) => {
+            entry["width"] = width.into();
+            entry["height"] = height.into();
+        }
+        None => entry["size"] = "auto".into(),
+    }
+    let p = serde_json::from_value(entry).map_err(|e| e.to_string())?;
+    Ok(Some((applet.clone(), p)))
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> zbus::Result<()> {
+    let args: Vec<_> = env::args().skip(1).collect();
+    let cli = parse_cli(&args).unwrap_or_else(|e| e.exit());
+    let inline = cli
+        .command
+        .target()
+        .map_or(Ok(None), inline_plasmoid)
+        .unwrap_or_else(|e| {
+            eprintln!("{e}");
+            std::process::exit(2);
+        });
+    let profile = cli.profile.as_deref();
+    let bus_address = cli.bus_address.as_deref();
+    match &cli.command {
+        Cmd::Version => {
+            print!("{}", version());
+            return Ok(());
+        }
+        Cmd::Applets => {
+            print!("{}", applets(cli.json));
+            return Ok(());
+        }
+        Cmd::SniList => match sni_list(cli.json, bus_address).await {
+            Ok(out) => {
+                print!("{out}");
+                return Ok(());
+            }
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        },
+        Cmd::Man => {
+            clap_mangen::Man::new(Cli::command())
+                .render(&mut std::io::stdout())
+                .expect("failed to write man page");
+            return Ok(());
+        }
+        Cmd::Reset => {
+            print!("{}", reset(&load_config(profile)));
+            return Ok(());
+        }
+        Cmd::Doctor => std::process::exit(if doctor(profile, bus_address).await {
+            0
+        } else {
+            1
+        }),
+        Cmd::Daemon { .. } => {}
+        // The daemon doesn't know about inline plasmoids, so run those here.
+        _ if inline.is_some() => {}
+        cmd => match forward(profile, &args).await {
+            Some(Ok(out)) => {
+                print!("{out}");
+                return Ok(());
+            }
+            Some(Err(e)) => {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+            None if cmd.daemon_only() => {
+                eprintln!("this command only applies to a running daemon");
+                std::process::exit(1);
+            }
+            None => {}
+        },
+    }
+
+    // Inline plasmoids don't need a config file to exist.
+    let mut cfg = if inline.is_some() && config_files(profile).is_empty() {
+        Config {
+            settings: Settings::default(),
+            plasmoids: IndexMap::new(),
+        }
+    } else {
+        load_config(profile)
+    };
+    if let Some((name, p)) = inline {
+        cfg.plasmoids.insert(name, p);
+    }
+    if cfg.plasmoids.is_empty() {
+        eprintln!("{NO_PLASMOIDS}");
+        std::process::exit(1);
+    }
+    let app = Rc::new(App {
+        cfg,
+        profile: cli.profile.clone(),
+        conn: match connect(bus_address).await {
+            Ok(conn) => Some(conn),
+            Err(e) if headless() => {
+                eprintln!("headless: no D-Bus ({e})");
+                None
+            }
+            Err(e) => return Err(e),
+        },
+        state: RefCell::default(),
+    });
+    if let Cmd::Daemon { toggle } = &cli.command {
+        LocalSet::new()
+            .run_until(daemon(app, toggle.as_deref(), bus_address))
+            .await;
+    } else {
+        // Let timers started by the command, like `peek`'s, run to completion.
+        let local = LocalSet::new();
+        match local.run_until(run(&app, &cli)).await {
+            Ok(out) => print!("{out}"),
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+        local.await;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use serde_json::json;
+
+    const ANCHORS: &[(&str, Anchor)] = &[
+        ("top-left", Anchor::TopLeft),
+        ("top", Anchor::Top),
+        ("top-right", Anchor::TopRight),
+        ("left", Anchor::Left),
+        ("center", Anchor::Center),
+        ("right", Anchor::Right),
+        ("bottom-left", Anchor::BottomLeft),
+        ("bottom", Anchor::Bottom),
+        ("bottom-right", Anchor::BottomRight),
+        ("focused-window", Anchor::FocusedWindow),
+        ("over-focused", Anchor::OverFocused),
+    ];
+
+    const SIZES: &[(&str, SizeMode)] = &[
+        ("fixed", SizeMode::Fixed),
+        ("auto", SizeMode::Auto),
+        ("remembered", SizeMode::Remembered),
+    ];
+
+    fn parse(value: Value) -> Result<Plasmoid, String> {
+        serde_json::from_value(value).map_err(|e| e.to_string())
+    }
+
+    fn parse_err(value: Value) -> String {
+        match parse(value) {
+            Ok(_) => panic!("invalid plasmoid was accepted"),
+            Err(e) => e,
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn missing_optional_fields_default(title in ".*", plasmoid in ".*") {
+            let p = parse(json!({ "title": title, "plasmoid": plasmoid })).unwrap();
+            prop_assert!(p.missing_size());
+            prop_assert_eq!(p.title, vec![title]);
+            prop_assert_eq!(p.plasmoid, plasmoid);
+            prop_assert!(p.exclusive && p.focus_on_show && p.focusable && p.detach && p.clamp);
+            prop_assert!(!p.title_prefix && !p.scrim && !p.sticky && !p.reposition_on_reshow);
+            prop_assert!(!p.follow_to_cursor_monitor && !p.mirror && !p.scale_with_monitor);
+            prop_assert!(p.size == SizeMode::Fixed);
+            prop_assert!(p.close_action == CloseAction::Close);
+            prop_assert!(p.anchor.is_none() && p.workspace.is_none() && p.bind.is_none());
+            prop_assert!(p.command.is_none() && p.class.is_none());
+            prop_assert!(p.on_show_dispatch.is_empty() && p.on_hide_dispatch.is_empty());
+            prop_assert_eq!(p.mode, Mode::Float);
+            prop_assert_eq!(p.min_visible_ms, 0);
+            prop_assert!(p.extra_rules.is_empty());
+            prop_assert!(!p.no_cycle);
+            prop_assert!(p.position.is_none());
+            prop_assert_eq!(p.sni_match, SniMatch::Id);
+            prop_assert_eq!((p.cooldown_ms, p.ready_delay_ms, p.idle_hide_ms), (0, 0, 0));
+        }
+
+        #[test]
+        fn optional_fields_round_trip(
+            titles in prop::collection::vec(".*", 1..4),
+            width: u32,
+            height: u32,
+            exclusive: bool,
+            focus_on_show: bool,
+            cooldown_ms: u64,
+            anchor in 0..ANCHORS.len(),
+            size in 0..SIZES.len(),
+            margin: (i64, i64, i64, i64),
+            custom_close in prop::option::of(".*"),
+        ) {
+            let close_action = match &custom_close {
+                Some(cmd) => json!({ "custom": cmd }),
+                None => json!("minimize-offscreen"),
+            };
+            let p = parse(json!({
+                "title": titles,
+                "plasmoid": "org.kde.plasma.calendar",
+                "width": width,
+                "height": height,
+                "exclusive": exclusive,
+                "focus_on_show": focus_on_show,
+                "cooldown_ms": cooldown_ms,
+                "anchor": ANCHORS[anchor].0,
+                "size": SIZES[size].0,
+                "margin": { "top": margin.0, "right": margin.1, "bottom": margin.2, "left": margin.3 },
+                "close_action": close_action,
+            }))
+            .unwrap();
+            prop_assert_eq!(p.title, titles);
+            prop_assert_eq!((p.width, p.height), (width, height));
+            prop_assert_eq!((p.exclusive, p.focus_on_show), (exclusive, focus_on_show));
+            prop_assert_eq!(p.cooldown_ms, cooldown_ms);
+            prop_assert!(p.anchor == Some(ANCHORS[anchor].1));
+            prop_assert!(p.size == SIZES[size].1);
+            let m = &p.margin;
+            prop_assert_eq!((m.top, m.right, m.bottom, m.left), margin);
+            let expected = match custom_close {
+                Some(cmd) => CloseAction::Custom(cmd),
+                None => CloseAction::MinimizeOffscreen,
+            };
+            prop_assert!(p.close_action == expected);
+        }
+
+        #[test]
+        fn negative_sizes_are_rejected(width in i64::MIN..0) {
+            let err = parse_err(json!({ "title": "t", "plasmoid": "p", "width": width }));
+            prop_assert!(err.contains("invalid value"), "{}", err);
+        }
+
+        #[test]
+        fn unknown_anchors_are_rejected(anchor in "[a-z-]{1,12}") {
+            prop_assume!(ANCHORS.iter().all(|(name, _)| *name != anchor));
+            let err = parse_err(json!({ "title": "t", "plasmoid": "p", "anchor": anchor }));
+            prop_assert!(err.contains("unknown variant"), "{}", err);
+        }
+
+        #[test]
+        fn every_entry_but_settings_is_a_plasmoid(
+            names in prop::collection::hash_set("[a-z0-9_-]{1,12}", 0..8),
+        ) {
+            let mut config = json!({ "settings": { "max_open": 2 } });
+            for name in &names {
+                config[name] = json!({ "title": name, "plasmoid": "p" });
+            }
+            prop_assume!(!names.contains("settings"));
+            let cfg: Config = serde_json::from_value(config).unwrap();
+            prop_assert_eq!(cfg.settings.max_open, Some(2));
+            prop_assert!(cfg.settings.manage_follow_mouse);
+            prop_assert_eq!(cfg.settings.sni_lookup.retries, 2);
+            prop_assert_eq!(cfg.plasmoids.len(), names.len());
+            for name in &names {
+                prop_assert_eq!(&cfg.plasmoids[name].title, &vec![name.clone()]);
+            }
+        }
+    }
+
+    fn monitor(x: i32, y: i32, width: u16, height: u16, scale: f32) -> Monitor {
+        serde_json::from_value(json!({
+            "id": 0, "name": "DP-1", "description": "", "width": width, "height": height,
+            "refreshRate": 60.0, "x": x, "y": y,
+            "activeWorkspace": { "id": 1, "name": "1" },
+            "specialWorkspace": { "id": 0, "name": "" },
+            "reserved": [0, 0, 0, 0], "scale": scale, "transform": 0, "focused": true,
+            "dpmsStatus": true, "vrr": false, "disabled": false,
+        }))
+        .unwrap()
+    }
+
+    fn geometry(mon: Monitor, cursor: (i64, i64), p: Value) -> (i64, i64, u32, u32) {
+        let snap = Snapshot {
+            clients: Vec::new(),
+            monitors: vec![mon],
+            cursor: Some(CursorPosition {
+                x: cursor.0,
+                y: cursor.1,
+            }),
+        };
+        let p = parse(p).unwrap();
+        window_geometry(&snap, "test", &p, p.requested_size()).unwrap()
+    }
+
+    fn assert_on_monitor(mon: &Monitor, (x, y, width, height): (i64, i64, u32, u32)) {
+        let area = monitor_rect(mon);
+        assert!(width > 0 && height > 0, "{width}x{height}");
+        assert!(
+            x >= area.x && x + width as i64 <= area.right(),
+            "x {x} w {width}"
+        );
+        assert!(
+            y >= area.y && y + height as i64 <= area.bottom(),
+            "y {y} h {height}"
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn windows_stay_on_extreme_monitors(
+            x in i32::MIN / 2..i32::MAX / 2,
+            y in i32::MIN / 2..i32::MAX / 2,
+            width in 3u16..,
+            height in 3u16..,
+            scale in prop::sample::select(vec![0.5f32, 1.0, 1.25, 3.0]),
+            cursor: (i64, i64),
+            size: (u32, u32),
+            anchor in prop::option::of(0..ANCHORS.len()),
+            margin: (i64, i64, i64, i64),
+        ) {
+            let mon = monitor(x, y, width, height, scale);
+            let mut p = json!({
+                "title": "t", "plasmoid": "p", "width": size.0, "height": size.1,
+                "margin": { "top": margin.0, "right": margin.1, "bottom": margin.2, "left": margin.3 },
+            });
+            if let Some(anchor) = anchor {
+                p["anchor"] = json!(ANCHORS[anchor].0);
+            }
+            let placed = geometry(mon.clone(), cursor, p);
+            assert_on_monitor(&mon, placed);
+        }
+    }
+
+    #[test]
+    fn negative_origin_high_resolution_monitor() {
+        let mon = monitor(-7680, -4320, 7680, 4320, 3.0);
+        let p = json!({ "title": "t", "plasmoid": "p", "width": 400, "height": 300 });
+        // 7680x4320 at scale 3 is 2560x1440 logical pixels.
+        let placed = geometry(mon.clone(), (-7680 + 100, -4320 + 100), p.clone());
+        assert_eq!(placed, (-7680 + 80, -4320 + 80, 400, 300));
+        let placed = geometry(mon.clone(), (i64::MAX, i64::MIN), p.clone());
+        assert_eq!(placed, (-7680 + 2560 - 20 - 400, -4320 + 20, 400, 300));
+
+        let mut anchored = p;
+        anchored["anchor"] = json!("bottom-right");
+        anchored["margin"] = json!({ "right": i64::MIN, "bottom": i64::MAX });
+        let placed = geometry(mon.clone(), (0, 0), anchored);
+        assert_eq!(placed, (-7680 + 2560 - 400, -4320, 400, 300));
+    }
+
+    #[test]
+    fn oversized_windows_shrink_to_tiny_monitors() {
+        let mon = monitor(i32::MIN, i32::MAX - 10, 10, 10, 3.0);
+        let p = json!({ "title": "t", "plasmoid": "p", "width": u32::MAX, "height": u32::MAX });
+        let placed = geometry(mon.clone(), (0, 0), p);
+        // 10 physical pixels at scale 3 leave a 3px wide area to clamp into.
+        assert_eq!(placed, (i32::MIN as i64 + 2, i32::MAX as i64 - 8, 1, 1));
+    }
+
+    #[test]
+    fn unclamped_windows_can_peek_past_the_edge() {
+        let mon = monitor(0, 0, 1920, 1080, 1.0);
+        let p = json!({
+            "title": "t", "plasmoid": "p", "width": 400, "height": 600,
+            "anchor": "left", "margin": { "left": -350 }, "clamp": false,
+        });
+        assert_eq!(
+            geometry(mon.clone(), (0, 0), p.clone()),
+            (-350, 240, 400, 600)
+        );
+        let mut clamped = p;
+        clamped["clamp"] = json!(true);
+        assert_eq!(geometry(mon, (0, 0), clamped), (0, 240, 400, 600));
+    }
+
+    #[tokio::test]
+    async fn peeking_windows_count_as_shown() {
+        let cfg: Config = serde_json::from_value(json!({
+            "drawer": {
+                "title": "Drawer", "plasmoid": "p", "width": 400, "height": 600,
+                "anchor": "left", "margin": { "left": -350 }, "clamp": false,
+            },
+        }))
+        .unwrap();
+        let mon = monitor(0, 0, 1920, 1080, 1.0);
+        let peeking = client("Drawer", (-350, 240), (400, 600), 0);
+        let parked = client("Drawer", (1920 + PADDING as i16, 0), (400, 600), 0);
+        assert!(is_onscreen(&peeking, std::slice::from_ref(&mon)));
+        assert!(!is_onscreen(&parked, std::slice::from_ref(&mon)));
+
+        let recorder = record(Recorder {
+            clients: vec![peeking],
+            monitors: vec![mon],
+            ..Recorder::default()
+        });
+        let app = App {
+            cfg,
+            profile: None,
+            conn: None,
+            state: RefCell::default(),
+        };
+        assert!(app.shown(&Snapshot::take(), "drawer").is_some());
+        LocalSet::new()
+            .run_until(toggle(&app, "drawer", false))
+            .await
+            .unwrap();
+        let calls = recorder.calls.borrow();
+        assert!(
+            calls.contains(&"closewindow address:0x1".to_string()),
+            "{calls:?}"
+        );
+    }
+
+    fn client(title: &str, at: (i16, i16), size: (i16, i16), focus_history_id: i8) -> Client {
+        serde_json::from_value(json!({
+            "address": "0x1", "at": at, "size": size,
+            "workspace": { "id": 1, "name": "1" },
+            "floating": false, "fullscreen": 0, "fullscreenClient": 0, "monitor": 0,
+            "initialClass": "app", "class": "app", "initialTitle": title, "title": title,
+            "pid": 1, "xwayland": false, "pinned": false, "grouped": [], "mapped": true,
+            "swallowing": null, "focusHistoryID": focus_history_id,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn plasmoids_go_beside_the_focused_window() {
+        let mut snap = Snapshot {
+            clients: vec![
+                client("editor", (100, 100), (800, 600), 1),
+                client("t", (1000, 0), (200, 200), 0),
+                client("browser", (0, 0), (1920, 1080), 2),
+            ],
+            monitors: vec![monitor(0, 0, 1920, 1080, 1.0)],
+            cursor: Some(CursorPosition { x: 500, y: 500 }),
+        };
+        let mut p = parse(json!({
+            "title": "t", "plasmoid": "p", "width": 300, "height": 400,
+            "anchor": "focused-window",
+        }))
+        .unwrap();
+        let size = p.requested_size();
+        // The plasmoid itself having focus doesn't count.
+        assert_eq!(
+            window_geometry(&snap, "t", &p, size),
+            Some((920, 100, 300, 400))
+        );
+        p.window_side = Side::Left;
+        p.window_gap = 0;
+        // Clamped onto the monitor.
+        assert_eq!(
+            window_geometry(&snap, "t", &p, size),
+            Some((0, 100, 300, 400))
+        );
+        p.window_side = Side::Below;
+        assert_eq!(
+            window_geometry(&snap, "t", &p, size),
+            Some((100, 680, 300, 400))
+        );
+
+        snap.clients.retain(|c| c.title == "t");
+        // Without another window it opens at the cursor.
+        assert_eq!(
+            window_geometry(&snap, "t", &p, size),
+            Some((480, 480, 300, 400))
+        );
+    }
+
+    #[test]
+    fn plasmoids_go_over_the_focused_window() {
+        let snap = Snapshot {
+            clients: vec![
+                client("editor", (100, 100), (800, 600), 1),
+                client("small", (1800, 1000), (100, 60), 2),
+            ],
+            monitors: vec![monitor(0, 0, 1920, 1080, 1.0)],
+            cursor: Some(CursorPosition { x: 500, y: 500 }),
+        };
+        let p = parse(json!({
+            "title": "t", "plasmoid": "p", "width": 300, "height": 400,
+            "anchor": "over-focused",
+        }))
+        .unwrap();
+        let size = p.requested_size();
+        assert_eq!(
+            window_geometry(&snap, "t", &p, size),
+            Some((350, 200, 300, 400))
+        );
+        // Centered over a window near the edge, it's clamped onto the monitor.
+        let mut snap = snap;
+        snap.clients.swap(0, 1);
+        snap.clients[0].focus_history_id = 0;
+        assert_eq!(
+            window_geometry(&snap, "t", &p, size),
+            Some((1620, 680, 300, 400))
+        );
+    }
+
+    fn positioned(snap: &Snapshot, position: Value) -> Option<(i64, i64, u32, u32)> {
+        let p = parse(json!({
+            "title": "t", "plasmoid": "p", "width": 300, "height": 200,
+            "position": position,
+        }))
+        .unwrap();
+        window_geometry(snap, "t", &p, p.requested_size())
+    }
+
+    #[test]
+    fn positions_relative_to_the_monitor() {
+        let snap = Snapshot {
+            clients: vec![],
+            monitors: vec![monitor(0, 0, 1920, 1080, 1.0)],
+            cursor: Some(CursorPosition { x: 500, y: 500 }),
+        };
+        let position = json!({ "anchor": "top-right", "offset": [-10, 10] });
+        assert_eq!(positioned(&snap, position), Some((1610, 10, 300, 200)));
+        // Centered on the monitor by default.
+        assert_eq!(positioned(&snap, json!({})), Some((810, 440, 300, 200)));
+        // Offsets past the edge are clamped back.
+        let position = json!({ "anchor": "top-left", "offset": [-50, -50] });
+        assert_eq!(positioned(&snap, position), Some((0, 0, 300, 200)));
+    }
+
+    #[test]
+    fn positions_relative_to_the_cursor() {
+        let snap = Snapshot {
+            clients: vec![],
+            monitors: vec![monitor(0, 0, 1920, 1080, 1.0)],
+            cursor: Some(CursorPosition { x: 500, y: 500 }),
+        };
+        let position =
+            json!({ "relative_to": "cursor", "anchor": "bottom-left", "offset": [5, -5] });
+        assert_eq!(positioned(&snap, position), Some((505, 295, 300, 200)));
+        let position = json!({ "relative_to": "cursor" });
+        assert_eq!(positioned(&snap, position), Some((350, 400, 300, 200)));
+    }
+
+    #[test]
+    fn positions_relative_to_the_focused_window() {
+        let mut snap = Snapshot {
+            clients: vec![client("editor", (100, 100), (800, 600), 1)],
+            monitors: vec![monitor(0, 0, 1920, 1080, 1.0)],
+            cursor: Some(CursorPosition { x: 500, y: 500 }),
+        };
+        let position =
+            json!({ "relative_to": "focused-window", "anchor": "top-left", "offset": [10, 10] });
+        assert_eq!(
+            positioned(&snap, position.clone()),
+            Some((110, 110, 300, 200))
+        );
+        let centered = json!({ "relative_to": "focused-window" });
+        assert_eq!(positioned(&snap, centered), Some((350, 300, 300, 200)));
+        // Without a window it's placed relative to the cursor.
+        snap.clients.clear();
+        assert_eq!(positioned(&snap, position), Some((510, 510, 300, 200)));
+        // The window is picked with relative_to, not the anchor.
+        let cfg: Config = serde_json::from_value(json!({
+            "clock": { "title": "t", "plasmoid": "p", "position": { "anchor": "focused-window" } },
+        }))
+        .unwrap();
+        assert_eq!(
+            check_config(&cfg).unwrap_err(),
+            "clock: position.anchor can't refer to a window, use relative_to"
+        );
+    }
+
+    #[test]
+    fn unclamped_positions_can_leave_the_monitor() {
+        let snap = Snapshot {
+            clients: vec![],
+            monitors: vec![monitor(0, 0, 1920, 1080, 1.0)],
+            cursor: Some(CursorPosition { x: 500, y: 500 }),
+        };
+        let p = parse(json!({
+            "title": "t", "plasmoid": "p", "width": 300, "height": 200, "clamp": false,
+            "position": { "anchor": "bottom-right", "offset": [100, 0] },
+        }))
+        .unwrap();
+        assert_eq!(
+            window_geometry(&snap, "t", &p, p.requested_size()),
+            Some((1720, 880, 300, 200))
+        );
+    }
+
+    #[test]
+    fn sizes_scale_with_the_monitor() {
+        let mut p =
+            parse(json!({ "title": "t", "plasmoid": "p", "width": 300, "height": 200 })).unwrap();
+        let hidpi = monitor(0, 0, 3840, 2160, 2.0);
+        assert_eq!(p.size_on(Some(&hidpi)), (300, 200));
+        p.scale_with_monitor = true;
+        assert_eq!(p.size_on(Some(&hidpi)), (600, 400));
+        assert_eq!(
+            p.size_on(Some(&monitor(0, 0, 1920, 1080, 1.25))),
+            (375, 250)
+        );
+        let mut broken = monitor(0, 0, 1920, 1080, 1.0);
+        broken.scale = f32::NAN;
+        assert_eq!(p.size_on(Some(&broken)), (300, 200));
+        assert_eq!(p.size_on(None), (300, 200));
+    }
+
+    #[test]
+    fn mirrors_are_placed_on_their_own_monitor() {
+        let mut side = monitor(-1920, 0, 1920, 1080, 1.0);
+        side.focused = false;
+        let snap = Snapshot {
+            clients: Vec::new(),
+            monitors: vec![monitor(0, 0, 2560, 1440, 1.0), side.clone()],
+            cursor: Some(CursorPosition { x: 100, y: 100 }),
+        };
+        let p = parse(json!({ "title": "t", "plasmoid": "p", "width": 400, "height": 200, "anchor": "center" }))
+            .unwrap();
+        let placed = geometry_on(&snap, &side, "test", &p, p.requested_size());
+        assert_eq!(placed, Some((-1920 + 760, 440, 400, 200)));
+        let placed = window_geometry(&snap, "test", &p, p.requested_size());
+        assert_eq!(placed, Some((1080, 620, 400, 200)));
+    }
+
+    #[test]
+    fn activate_point_saturates_to_i32() {
+        assert_eq!(saturate_i32(i64::MAX), i32::MAX);
+        assert_eq!(saturate_i32(i64::MIN), i32::MIN);
+        assert_eq!(saturate_i32(-42), -42);
+    }
+
+    #[test]
+    fn required_fields_and_empty_titles_are_rejected() {
+        let err = parse_err(json!({ "plasmoid": "p" }));
+        assert!(err.contains("missing field `title`"), "{err}");
+        let err = parse_err(json!({ "title": [], "plasmoid": "p" }));
+        assert!(err.contains("title list is empty"), "{err}");
+        let err = parse_err(json!({ "title": 1, "plasmoid": "p" }));
+        assert!(err.contains("did not match any variant"), "{err}");
+    }
+
+    #[test]
+    fn config_files_allow_comments_and_trailing_commas() {
+        let path = env::temp_dir().join(format!("hypr-plasmoid-{}.json", std::process::id()));
+        fs::write(
+            &path,
+            r#"{
+                // The clock
+                "clock": { "title": "Clock", "plasmoid": "org.kde.plasma.digitalclock", },
+                /* and the calendar */
+                "calendar": { "title": "Calendar", "plasmoid": "org.kde.plasma.calendar" },
+            }"#,
+        )
+        .unwrap();
+        let value = read_config_file(&path);
+        fs::remove_file(&path).ok();
+        let cfg: Config = serde_json::from_value(value.unwrap()).unwrap();
+        let names: Vec<_> = cfg.plasmoids.keys().collect();
+        assert_eq!(names, ["clock", "calendar"]);
+    }
+
+    #[test]
+    fn descendants_are_found_through_proc() {
+        let pid = std::process::id();
+        let parent = std::os::unix::process::parent_id();
+        assert!(descends_from(pid as i32, pid));
+        assert!(descends_from(pid as i32, parent));
+        assert!(!descends_from(parent as i32, pid));
+        assert!(!descends_from(-1, pid));
+    }
+
+    #[test]
+    fn applet_flags_parse_after_global_flags() {
+        let parse = |line: &str| parse_cli(&line.split(' ').map(String::from).collect::<Vec<_>>());
+        let cli =
+            parse("--profile work which --applet org.kde.plasma.notes --size 300x200").unwrap();
+        let target = cli.command.target().unwrap();
+        assert_eq!(target.name(), "org.kde.plasma.notes");
+        assert_eq!(target.size, Some((300, 200)));
+        let cli = parse("--bus-address unix:path=/bus toggle clock").unwrap();
+        assert_eq!(cli.command.target().unwrap().name(), "clock");
+        assert!(parse("toggle clock --applet org.kde.plasma.notes").is_err());
+        assert!(parse("toggle clock --size 300x200").is_err());
+        assert!(parse("toggle --applet org.kde.plasma.notes --size 300").is_err());
+    }
+
+    #[test]
+    fn binds_toggle_through_the_same_profile_and_bus() {
+        let exe = Path::new("/usr/bin/hypr-plasmoid");
+        assert_eq!(
+            bind_command(exe, None, None, "clock"),
+            "'/usr/bin/hypr-plasmoid' toggle 'clock'"
+        );
+        assert_eq!(
+            bind_command(exe, Some("work"), Some("unix:path=/run/bus"), "it's"),
+            "'/usr/bin/hypr-plasmoid' --profile 'work' --bus-address 'unix:path=/run/bus' \
+             toggle 'it'\\''s'"
+        );
+    }
+
+    #[test]
+    fn sticky_plasmoids_let_go_of_focus() {
+        let cfg: Config = serde_json::from_value(json!({
+            "clock": { "title": "Clock", "plasmoid": "p", "size": "auto", "sticky": true },
+            "notes": { "title": "Notes", "plasmoid": "q", "size": "auto" },
+            "volume": { "title": "Volume", "plasmoid": "r", "size": "auto" },
+        }))
+        .unwrap();
+        let app = App {
+            cfg,
+            profile: None,
+            conn: None,
+            state: RefCell::default(),
+        };
+        record(Recorder::default());
+        app.state.borrow_mut().sticky.insert("notes".into());
+        for name in ["clock", "notes", "volume"] {
+            app.acquire_focus(name);
+        }
+        app.release_sticky_focus();
+        let holders = app.state.borrow().focus_holders.clone();
+        assert_eq!(holders, HashSet::from(["volume".to_string()]));
+    }
+
+    #[tokio::test]
+    async fn unknown_names_are_reported() {
+        let cfg: Config = serde_json::from_value(json!({
+            "clock": { "title": "Clock", "plasmoid": "p", "size": "auto" },
+        }))
+        .unwrap();
+        let app = Rc::new(App {
+            cfg,
+            profile: None,
+            conn: None,
+            state: RefCell::default(),
+        });
+        for line in ["toggle foo", "cycle clock foo", "which foo"] {
+            let args: Vec<_> = line.split(' ').map(String::from).collect();
+            let err = run(&app, &parse_cli(&args).unwrap()).await.unwrap_err();
+            assert_eq!(err.to_string(), "unknown plasmoid foo");
+        }
+    }
+
+    #[test]
+    fn profiles_keep_their_files_apart() {
+        let args = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+        let cli = parse_cli(&args(&["list", "--profile", "work"])).unwrap();
+        assert_eq!(cli.profile.as_deref(), Some("work"));
+        assert!(parse_cli(&args(&["--profile", "../work", "list"])).is_err());
+        assert_eq!(
+            profile_file_name("hypr-plasmoid", None, "sock"),
+            "hypr-plasmoid.sock"
+        );
+        assert_eq!(
+            profile_file_name("hypr-plasmoid", Some("work"), "sock"),
+            "hypr-plasmoid.work.sock"
+        );
+    }
+
+    fn focus(last_active: &mut Option<String>, target: FocusTarget) -> FocusAction {
+        on_active_window(last_active, target, |name| name != "shared")
+    }
+
+    fn plasmoid(name: &str) -> FocusTarget {
+        FocusTarget::Plasmoid(Some(name.into()))
+    }
+
+    fn enter(name: &str, hide: Option<&str>) -> FocusAction {
+        FocusAction::Enter {
+            name: Some(name.into()),
+            hide: hide.map(String::from),
+        }
+    }
+
+    #[test]
+    fn focus_entering_and_leaving_plasmoids() {
+        let mut last = None;
+        assert_eq!(focus(&mut last, plasmoid("clock")), enter("clock", None));
+        assert_eq!(last.as_deref(), Some("clock"));
+        assert_eq!(focus(&mut last, FocusTarget::Friendly), FocusAction::Stay);
+        assert_eq!(last.as_deref(), Some("clock"));
+        assert_eq!(focus(&mut last, FocusTarget::Other), FocusAction::Leave);
+        assert_eq!(last, None);
+        assert_eq!(focus(&mut last, FocusTarget::Other), FocusAction::Leave);
+    }
+
+    #[test]
+    fn focus_moving_between_plasmoids_hides_exclusive_ones() {
+        let mut last = None;
+        focus(&mut last, plasmoid("clock"));
+        assert_eq!(
+            focus(&mut last, plasmoid("shared")),
+            enter("shared", Some("clock"))
+        );
+        // Non-exclusive plasmoids are left open.
+        assert_eq!(focus(&mut last, plasmoid("clock")), enter("clock", None));
+        // An untracked window with a plasmoid's title hides nothing.
+        assert_eq!(
+            focus(&mut last, FocusTarget::Plasmoid(None)),
+            FocusAction::Enter {
+                name: None,
+                hide: None
+            }
+        );
+        assert_eq!(last, None);
+    }
+
+    #[test]
+    fn rapid_refocusing_hides_nothing_twice() {
+        let mut last = None;
+        for _ in 0..3 {
+            assert_eq!(focus(&mut last, plasmoid("clock")), enter("clock", None));
+        }
+        assert_eq!(
+            focus(&mut last, plasmoid("calendar")),
+            enter("calendar", Some("clock"))
+        );
+        assert_eq!(
+            focus(&mut last, plasmoid("clock")),
+            enter("clock", Some("calendar"))
+        );
+        assert_eq!(focus(&mut last, FocusTarget::Other), FocusAction::Leave);
+        assert_eq!(
+            focus(&mut last, plasmoid("calendar")),
+            enter("calendar", None)
+        );
+    }
+
+    #[test]
+    fn defaults_fill_in_what_entries_leave_out() {
+        let Value::Object(mut merged) = json!({
+            "settings": { "max_open": 2 },
+            "defaults": { "anchor": "top-right", "margin": { "top": 8, "right": 8 } },
+            "clock": { "title": "Clock", "plasmoid": "p", "margin": { "top": 40 } },
+            "notes": { "title": "Notes", "plasmoid": "q", "anchor": "center" },
+        }) else {
+            unreachable!()
+        };
+        apply_defaults(&mut merged).unwrap();
+        let cfg: Config = serde_json::from_value(Value::Object(merged)).unwrap();
+        assert_eq!(cfg.plasmoids.len(), 2);
+        let clock = &cfg.plasmoids["clock"];
+        assert!(clock.anchor == Some(Anchor::TopRight));
+        assert_eq!((clock.margin.top, clock.margin.right), (40, 8));
+        assert!(cfg.plasmoids["notes"].anchor == Some(Anchor::Center));
+
+        let Value::Object(mut merged) = json!({ "defaults": [] }) else {
+            unreachable!()
+        };
+        assert_eq!(
+            apply_defaults(&mut merged).unwrap_err(),
+            "defaults: expected a JSON object"
+        );
+    }
+
+    #[test]
+    fn dumped_config_loads_back_the_same() {
+        let cfg: Config = serde_json::from_value(json!({
+            "settings": { "max_open": 2 },
+            "clock": { "title": ["Clock", "Uhr"], "plasmoid": "p", "anchor": "top-right" },
+            "notes": { "title": "Notes", "command": "notes", "close_action": { "custom": "movetoworkspacesilent 9" } },
+        }))
+        .unwrap();
+        let dumped = serde_json::to_value(&cfg).unwrap();
+        let reloaded: Config = serde_json::from_value(dumped.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&reloaded).unwrap(), dumped);
+        assert_eq!(dumped["clock"]["title"], json!(["Clock", "Uhr"]));
+        assert_eq!(dumped["notes"]["exclusive"], json!(true));
+    }
+
+    #[tokio::test]
+    async fn hide_all_closes_everything_but_the_excepted_plasmoid() {
+        let cfg: Config = serde_json::from_value(json!({
+            "clock": { "title": "Clock", "plasmoid": "p" },
+            "notes": { "title": "Notes", "plasmoid": "q" },
+            "volume": { "title": "Volume", "plasmoid": "r" },
+        }))
+        .unwrap();
+        let clients: Vec<_> = ["Clock", "Notes", "Volume"]
+            .into_iter()
+            .enumerate()
+            .map(|(i, title)| Client {
+                address: Address::new(format!("0x{}", i + 1)),
+                ..client(title, (0, 0), (10, 10), 0)
+            })
+            .collect();
+        let recorder = record(Recorder {
+            clients: clients.clone(),
+            ..Recorder::default()
+        });
+        let app = App {
+            cfg,
+            profile: None,
+            conn: None,
+            state: RefCell::default(),
+        };
+        let closed = |address: &str| {
+            let call = format!("closewindow address:{address}");
+            recorder.calls.borrow().contains(&call)
+        };
+        let focus_off = || {
+            let call = "keyword input:follow_mouse 1".to_string();
+            recorder.calls.borrow().contains(&call)
+        };
+        LocalSet::new()
+            .run_until(async {
+                for name in ["clock", "notes", "volume"] {
+                    app.acquire_focus(name);
+                }
+                recorder.calls.borrow_mut().clear();
+                hide_all(&app, &clients, Some("notes")).await;
+                assert!(closed("0x1") && closed("0x3"));
+                assert!(!closed("0x2"));
+                // Notes still holds focus.
+                assert!(!focus_off());
+
+                recorder.calls.borrow_mut().clear();
+                hide_all(&app, &clients, None).await;
+                assert!(closed("0x2"));
+                assert!(focus_off());
+            })
+            .await;
+    }
+
+    #[test]
+    fn extra_rules_split_into_prop_and_value() {
+        assert_eq!(extra_rule("opacity 0.9 0.8"), ("opacity", "0.9 0.8"));
+        assert_eq!(extra_rule("  border_size   2 "), ("border_size", "2"));
+        assert_eq!(extra_rule("pin"), ("pin", "yes"));
+    }
+
+    #[test]
+    fn escaped_anchors_in_titles_match_literally() {
+        let title = "Price: $5^2";
+        for pattern in [r"Price: \$5\^2".to_string(), regex::escape(title)] {
+            let mut entry = json!({ "title": pattern, "plasmoid": "p" });
+            expand_env_values(&mut entry).unwrap();
+            let mut p = parse(entry).unwrap();
+            for prefix in [false, true] {
+                p.title_prefix = prefix;
+                let matches = client_matcher(&p).unwrap();
+                assert!(matches(&client(title, (0, 0), (10, 10), 0)));
+                assert!(!matches(&client("Price: 5", (0, 0), (10, 10), 0)));
+                assert_eq!(
+                    find_matching_client(&[client(title, (0, 0), (10, 10), 0)], &p)
+                        .map(|c| c.title.as_str()),
+                    Some(title)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn entries_need_a_plasmoid_or_a_command() {
+        let config = |entry: Value| -> Config {
+            serde_json::from_value(json!({ "settings": {}, "clock": entry })).unwrap()
+        };
+        let cfg = config(json!({ "title": "t" }));
+        assert_eq!(
+            check_config(&cfg).unwrap_err(),
+            "clock: needs a plasmoid or a command"
+        );
+        let cfg = config(json!({
+            "title": "t", "command": "gnome-clocks", "class": "clocks", "size": "auto",
+        }));
+        assert!(check_config(&cfg).is_ok());
+        let cfg = config(json!({
+            "title": "t", "plasmoid": "org.kde.plasma.digitalclock", "width": 300, "height": 200,
+        }));
+        assert!(check_config(&cfg).is_ok());
+    }
+
+    #[test]
+    fn fixed_sizes_need_a_width_and_height() {
+        let check = |entry: Value| {
+            let cfg: Config = serde_json::from_value(json!({ "clock": entry })).unwrap();
+            check_config(&cfg)
+        };
+        let missing = "clock: needs a width and height, or size auto or remembered";
+        let entry = json!({ "title": "t", "plasmoid": "p" });
+        assert_eq!(check(entry).unwrap_err(), missing);
+        let entry = json!({ "title": "t", "plasmoid": "p", "width": 300, "height": 0 });
+        assert_eq!(check(entry).unwrap_err(), missing);
+        for size in ["auto", "remembered"] {
+            assert!(check(json!({ "title": "t", "plasmoid": "p", "size": size })).is_ok());
+        }
+        assert!(check(json!({ "title": "t", "plasmoid": "p", "mode": "special" })).is_ok());
+    }
+
+    #[test]
+    fn special_mode_parses_and_rejects_mirror() {
+        let p = parse(json!({ "title": "t", "plasmoid": "p", "mode": "special" })).unwrap();
+        assert_eq!(p.mode, Mode::Special);
+        let cfg: Config = serde_json::from_value(json!({
+            "clock": { "title": "t", "plasmoid": "p", "mode": "special", "mirror": true },
+        }))
+        .unwrap();
+        assert_eq!(
+            check_config(&cfg).unwrap_err(),
+            "clock: mirror doesn't work with mode special"
+        );
+    }
+
+    fn record(recorder: Recorder) -> Rc<Recorder> {
+        let recorder = Rc::new(recorder);
+        COMPOSITOR.set(recorder.clone());
+        recorder
+    }
+
+    // Whatever the real socket answers, or nothing without a Hyprland
+    // session, it's never what the installed compositor would have.
+    #[test]
+    fn hyprland_queries_go_to_the_socket() {
+        let mut mon = monitor(0, 0, 4242, 4242, 1.0);
+        mon.name = "RECORDED-1".into();
+        mon.active_workspace.id = 4242;
+        record(Recorder {
+            clients: vec![client("recorded", (0, 0), (10, 10), 0)],
+            monitors: vec![mon],
+            cursor: Some(CursorPosition { x: 4242, y: 4242 }),
+            active: Some(Address::new("0x4242")),
+            ..Recorder::default()
+        });
+        let hyprland = Hyprland;
+        assert!(hyprland.clients().iter().all(|c| c.title != "recorded"));
+        assert!(hyprland.monitors().iter().all(|m| m.name != "RECORDED-1"));
+        assert_ne!(hyprland.cursor().map(|c| c.x), Some(4242));
+        assert_ne!(hyprland.active_window(), Some(Address::new("0x4242")));
+        assert_ne!(hyprland.active_workspace(), Some(4242));
+        assert_ne!(hyprland.version().ok().as_deref(), Some("none (headless)"));
+    }
+
+    #[test]
+    fn position_command_runs_once_per_show() {
+        let log = env::temp_dir().join(format!("hypr-plasmoid-runs-{}", std::process::id()));
+        let command = format!("echo run >> '{}'; echo 10 20", log.display());
+        let p = parse(json!({
+            "title": "Clock", "plasmoid": "p", "width": 400, "height": 300,
+            "anchor": "top", "position_command": command,
+        }))
+        .unwrap();
+        record(Recorder {
+            clients: vec![client("Clock", (10, 20), (400, 300), 0)],
+            ..Recorder::headless()
+        });
+        let snap = Snapshot::take();
+        let placed = set_window_rules(&snap, "clock", &p);
+        assert_eq!(placed, Some((10, 20, 400, 300)));
+        assert_eq!(activate_point(&snap, &p, placed), (210, 20));
+        honor_size_limits(&snap, "clock", &p, placed, &Address::new("0x1"));
+        let runs = fs::read_to_string(&log).unwrap();
+        fs::remove_file(&log).ok();
+        assert_eq!(runs.lines().count(), 1);
+    }
+
+    #[test]
+    fn headless_places_windows_on_the_synthetic_monitor() {
+        let recorder = record(Recorder::headless());
+        let p =
+            parse(json!({ "title": "t", "plasmoid": "p", "width": 400, "height": 300 })).unwrap();
+        set_window_rules(&Snapshot::take(), "clock", &p);
+        let calls = recorder.calls.borrow();
+        for rule in ["float yes", "size 400 300", "move "] {
+            let call = format!("keyword windowrule[hypr-plasmoid-clock]:{rule}");
+            assert!(
+                calls.iter().any(|c| c.starts_with(&call)),
+                "no {call} in {calls:?}"
+            );
+        }
     }
-    Ok(())
 }